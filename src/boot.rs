@@ -0,0 +1,187 @@
+// Boot sectors: loaded by the BIOS at 0000:7C00, optionally with a FAT BIOS Parameter Block
+// between the initial jump and the code.
+
+use std::fmt::Write;
+
+use crate::format::write_db;
+
+pub const ORIGIN: u32 = 0x7C00;
+pub const SECTOR_SIZE: usize = 512;
+pub const SIGNATURE_OFFSET: usize = 510;
+const SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const JMP_SHORT: u8 = 0xEB;
+const JMP_NEAR: u8 = 0xE9;
+const EXTENDED_BOOT_SIGNATURES: [u8; 2] = [0x28, 0x29];
+
+// Offset, size and label of each field, sizes other than 1, 2 and 4 are character fields
+const BPB_FIELDS: [(usize, usize, &str); 13] = [
+    (0x03, 8, "oem_name"),
+    (0x0B, 2, "bytes_per_sector"),
+    (0x0D, 1, "sectors_per_cluster"),
+    (0x0E, 2, "reserved_sectors"),
+    (0x10, 1, "fat_count"),
+    (0x11, 2, "root_entries"),
+    (0x13, 2, "total_sectors"),
+    (0x15, 1, "media_descriptor"),
+    (0x16, 2, "sectors_per_fat"),
+    (0x18, 2, "sectors_per_track"),
+    (0x1A, 2, "heads"),
+    (0x1C, 4, "hidden_sectors"),
+    (0x20, 4, "total_sectors_32"),
+];
+// DOS 4.0 extension used by FAT12 and FAT16
+const EBPB_FIELDS: [(usize, usize, &str); 6] = [
+    (0x24, 1, "drive_number"),
+    (0x25, 1, "reserved_nt"),
+    (0x26, 1, "boot_signature"),
+    (0x27, 4, "volume_id"),
+    (0x2B, 11, "volume_label"),
+    (0x36, 8, "filesystem_type"),
+];
+const FAT32_EBPB_FIELDS: [(usize, usize, &str); 13] = [
+    (0x24, 4, "sectors_per_fat_32"),
+    (0x28, 2, "mirror_flags"),
+    (0x2A, 2, "version"),
+    (0x2C, 4, "root_cluster"),
+    (0x30, 2, "fsinfo_sector"),
+    (0x32, 2, "backup_boot_sector"),
+    (0x34, 12, "reserved_fat32"),
+    (0x40, 1, "drive_number"),
+    (0x41, 1, "reserved_nt"),
+    (0x42, 1, "boot_signature"),
+    (0x43, 4, "volume_id"),
+    (0x47, 11, "volume_label"),
+    (0x52, 8, "filesystem_type"),
+];
+
+pub fn has_signature(sector: &[u8]) -> bool {
+    sector[SIGNATURE_OFFSET..SECTOR_SIZE] == SIGNATURE
+}
+
+// Size of the initial jump (including the byte after a jmp short, usually a nop) and the sector
+// offset it jumps to, boot sectors without a BPB (MBRs) start with code instead
+pub fn initial_jump(sector: &[u8]) -> Option<(usize, usize)> {
+    let (size, next, disp): (usize, usize, isize) = match sector[0] {
+        JMP_SHORT => (3, 2, sector[1] as i8 as isize),
+        JMP_NEAR => (3, 3, i16::from_le_bytes([sector[1], sector[2]]) as isize),
+        _ => return None,
+    };
+    let target = next.checked_add_signed(disp)?;
+    (size..SIGNATURE_OFFSET)
+        .contains(&target)
+        .then_some((size, target))
+}
+
+// The fields that fit before the code starts, the layout is picked from the signatures it contains
+fn fields(sector: &[u8], code_start: usize) -> Vec<(usize, usize, &'static str)> {
+    let mut fields = BPB_FIELDS.to_vec();
+    if code_start >= 0x5A
+        && EXTENDED_BOOT_SIGNATURES.contains(&sector[0x42])
+        && sector[0x52..0x57] == *b"FAT32"
+    {
+        fields.extend(FAT32_EBPB_FIELDS);
+    } else if code_start >= 0x3E && EXTENDED_BOOT_SIGNATURES.contains(&sector[0x26]) {
+        fields.extend(EBPB_FIELDS);
+    }
+    fields.retain(|&(offset, size, _)| offset + size <= code_start);
+    fields
+}
+
+// Labelled data for the bytes between the initial jump and the code
+pub fn print_bpb(sector: &[u8], start: usize, code_start: usize) {
+    let mut line = String::new();
+    let mut offset = start;
+    for (field_offset, size, label) in fields(sector, code_start) {
+        let bytes = &sector[field_offset..field_offset + size];
+        line.clear();
+        match size {
+            1 => write!(line, "{label}: db {:#x}", bytes[0]).unwrap(),
+            2 => write!(
+                line,
+                "{label}: dw {:#x}",
+                u16::from_le_bytes([bytes[0], bytes[1]])
+            )
+            .unwrap(),
+            4 => write!(
+                line,
+                "{label}: dd {:#x}",
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            )
+            .unwrap(),
+            _ => {
+                write!(line, "{label}: ").unwrap();
+                write_db(&mut line, bytes);
+            }
+        }
+        println!("{line}");
+        offset = field_offset + size;
+    }
+
+    // Boot code that does not start right after the BPB keeps the rest as plain bytes
+    if offset < code_start {
+        line.clear();
+        write_db(&mut line, &sector[offset..code_start]);
+        println!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuMode;
+    use crate::format::Syntax;
+    use crate::tests::listing;
+
+    // A FAT12 sector with the extended BPB, code at 3Eh and the start of a message behind it
+    fn fat12_sector() -> [u8; SECTOR_SIZE] {
+        let mut sector = [0; SECTOR_SIZE];
+        sector[..3].copy_from_slice(&[JMP_SHORT, 0x3C, 0x90]);
+        sector[0x26] = 0x29;
+        sector[0x3E..0x41].copy_from_slice(&[0xEB, 0xFE, 0x90]);
+        sector[0x41..0x4A].copy_from_slice(b"Non-syst\0");
+        sector[SIGNATURE_OFFSET..].copy_from_slice(&SIGNATURE);
+        sector
+    }
+
+    #[test]
+    fn jump_over_the_bpb_picks_the_fields() {
+        let sector = fat12_sector();
+        assert!(has_signature(&sector));
+        assert_eq!(initial_jump(&sector), Some((3, 0x3E)));
+        let labels: Vec<_> = fields(&sector, 0x3E).iter().map(|field| field.2).collect();
+        assert_eq!(labels.len(), 19);
+        assert_eq!(labels.last(), Some(&"filesystem_type"));
+        // Without the extended boot signature only the DOS 3.31 fields are there
+        let mut sector = sector;
+        sector[0x26] = 0;
+        assert_eq!(fields(&sector, 0x3E).len(), 13);
+    }
+
+    #[test]
+    fn message_text_is_decoded_past_undefined_opcodes() {
+        let sector = fat12_sector();
+        assert_eq!(
+            listing(&sector[0x3E..0x49], CpuMode::I8086, Syntax::Intel),
+            [
+                "jmp short $+2+-2",
+                "xchg ax, ax",
+                "dec si",
+                "db 0x6f ; invalid encoding",
+                "db 0x6e ; invalid encoding",
+                "sub ax, 31091",
+                "jnb $+2+116",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_sector_starting_with_code_has_no_jump() {
+        let mut sector = [0; SECTOR_SIZE];
+        sector[0] = 0xFA;
+        assert_eq!(initial_jump(&sector), None);
+        // A jump back into the jump itself does not skip a BPB
+        sector[..2].copy_from_slice(&[JMP_SHORT, 0xFF]);
+        assert_eq!(initial_jump(&sector), None);
+        assert!(!has_signature(&sector));
+    }
+}
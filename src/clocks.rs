@@ -0,0 +1,256 @@
+// Clock counts from the timing tables of the Intel 8086 and 8088 manuals. An instruction takes its
+// base clocks, the clocks to compute its effective address, 2 more for a segment override prefix
+// and 4 for every word the 8086 moves at an odd address. The 8088 moves every word as two bytes,
+// which costs the 4 clocks on each word whatever its address.
+
+use std::fmt;
+
+use crate::cpu::Cycles;
+use crate::{EffectiveAddress, Instruction, Operand};
+
+#[derive(Clone, Copy, Default)]
+pub struct Clocks {
+    pub base: u32,
+    pub effective_address: u32,
+    pub segment_override: u32,
+    // Extra bus cycles of word transfers
+    pub transfers: u32,
+}
+
+impl Clocks {
+    pub fn total(&self) -> u32 {
+        self.base + self.effective_address + self.segment_override + self.transfers
+    }
+}
+
+// "8 + 9ea + 2seg + 4p", the parts that are 0 are left out
+impl fmt::Display for Clocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.base)?;
+        for (clocks, suffix) in [
+            (self.effective_address, "ea"),
+            (self.segment_override, "seg"),
+            (self.transfers, "p"),
+        ] {
+            if clocks != 0 {
+                write!(f, " + {clocks}{suffix}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn estimate(instruction: &Instruction, cycles: &Cycles, i8088: bool) -> Clocks {
+    let address = instruction.operands.iter().find_map(|operand| match operand {
+        Operand::Mem { address, .. } => Some(address),
+        _ => None,
+    });
+    let transfers = if i8088 {
+        cycles.word_transfers
+    } else {
+        cycles.odd_transfers
+    };
+    // The single-step interrupt takes 50 clocks
+    let trap = if cycles.trapped { 50 } else { 0 };
+    Clocks {
+        base: base(instruction, cycles) + trap,
+        effective_address: if accumulator_move(instruction) {
+            0
+        } else {
+            address.map_or(0, effective_address)
+        },
+        segment_override: if instruction.prefixes.segment.is_some() {
+            2
+        } else {
+            0
+        },
+        transfers: 4 * transfers,
+    }
+}
+
+// mov between the accumulator and a direct address without a ModR/M byte, A0-A3, which computes
+// no effective address
+fn accumulator_move(instruction: &Instruction) -> bool {
+    use EffectiveAddress::Direct;
+    use Operand::{Mem, Reg};
+
+    let opcode_size = instruction.size - instruction.prefixes.count();
+    instruction.mnemonic == "mov"
+        && opcode_size == 3
+        && matches!(
+            instruction.operands[..],
+            [Reg(0, _), Mem { address: Direct(_), .. }] | [Mem { address: Direct(_), .. }, Reg(0, _)]
+        )
+}
+
+fn effective_address(address: &EffectiveAddress) -> u32 {
+    match *address {
+        EffectiveAddress::Direct(_) => 6,
+        EffectiveAddress::Indirect(base_idx, disp, _) => {
+            // bx + si and bp + di are a clock faster than bx + di and bp + si
+            match (base_idx, disp.is_some()) {
+                (0 | 3, false) => 7,
+                (1 | 2, false) => 8,
+                (0 | 3, true) => 11,
+                (1 | 2, true) => 12,
+                (_, false) => 5,
+                (_, true) => 9,
+            }
+        }
+        EffectiveAddress::Reg(_) | EffectiveAddress::Indirect32 { .. } => 0,
+    }
+}
+
+fn base(instruction: &Instruction, cycles: &Cycles) -> u32 {
+    use Operand::{Far, Imm, Mem, Reg, SegReg};
+
+    // The short forms with the register in the opcode or with the accumulator and no ModR/M byte
+    let opcode_size = instruction.size - instruction.prefixes.count();
+    let branch = |taken: u32, not_taken: u32| if cycles.taken { taken } else { not_taken };
+    let mnemonic = instruction.mnemonic;
+    match (mnemonic, &instruction.operands[..]) {
+        ("mov", _) if accumulator_move(instruction) => 10,
+        ("mov", [Reg(..) | SegReg(_), Reg(..) | SegReg(_)]) => 2,
+        ("mov", [Reg(..) | SegReg(_), Mem { .. }]) => 8,
+        ("mov", [Mem { .. }, Reg(..) | SegReg(_)]) => 9,
+        ("mov", [Reg(..), Imm(_)]) => 4,
+        ("mov", [Mem { .. }, Imm(_)]) => 10,
+        ("xchg", [Reg(..), Reg(..)]) if opcode_size == 1 => 3,
+        ("xchg", [Reg(..), Reg(..)]) => 4,
+        ("xchg", [_, _]) => 17,
+        ("lea", _) => 2,
+        ("lds" | "les", _) => 16,
+        ("add" | "or" | "adc" | "sbb" | "and" | "sub" | "xor" | "cmp", operands) => {
+            match operands {
+                [Reg(..), Reg(..)] => 3,
+                [Reg(..), Imm(_)] => 4,
+                [Reg(..), Mem { .. }] => 9,
+                [Mem { .. }, Reg(..)] if mnemonic == "cmp" => 9,
+                [Mem { .. }, Reg(..)] => 16,
+                [Mem { .. }, Imm(_)] if mnemonic == "cmp" => 10,
+                _ => 17,
+            }
+        }
+        ("test", [Reg(..), Reg(..)]) => 3,
+        ("test", [Reg(0, width), Imm(_)]) if opcode_size == 2 + width => 4,
+        ("test", [Reg(..), Imm(_)]) => 5,
+        ("test", [Mem { .. }, Imm(_)]) => 11,
+        ("test", _) => 9,
+        ("inc" | "dec", [Reg(..)]) if opcode_size == 1 => 2,
+        ("inc" | "dec", [Reg(..)]) => 3,
+        ("inc" | "dec", _) => 15,
+        ("neg" | "not", [Reg(..)]) => 3,
+        ("neg" | "not", _) => 16,
+        // The slowest case of the range the tables give, the 8086 takes longer for more 1 bits
+        ("mul", [Reg(_, 0)]) => 77,
+        ("mul", [Reg(..)]) => 133,
+        ("mul", [Mem { width: 0, .. }]) => 83,
+        ("mul", _) => 139,
+        ("imul", [Reg(_, 0)]) => 98,
+        ("imul", [Reg(..)]) => 154,
+        ("imul", [Mem { width: 0, .. }]) => 104,
+        ("imul", _) => 160,
+        ("div", [Reg(_, 0)]) => 90,
+        ("div", [Reg(..)]) => 162,
+        ("div", [Mem { width: 0, .. }]) => 96,
+        ("div", _) => 168,
+        ("idiv", [Reg(_, 0)]) => 112,
+        ("idiv", [Reg(..)]) => 184,
+        ("idiv", [Mem { width: 0, .. }]) => 118,
+        ("idiv", _) => 190,
+        ("aam", _) => 83,
+        ("aad", _) => 60,
+        ("daa" | "das" | "aaa" | "aas", _) => 4,
+        ("cbw", _) => 2,
+        ("cwd", _) => 5,
+        ("rol" | "ror" | "rcl" | "rcr" | "shl" | "shr" | "sar", [dst, count]) => {
+            // By 1, or by cl at 4 clocks a bit
+            match (dst, count) {
+                (Reg(..), Imm(_)) => 2,
+                (_, Imm(_)) => 15,
+                (Reg(..), _) => 8 + 4 * cycles.repeat,
+                _ => 20 + 4 * cycles.repeat,
+            }
+        }
+        ("jmp", [Far(..)]) => 15,
+        ("jmp", [Reg(..)]) => 11,
+        ("jmp", [Mem { .. }]) if instruction.far => 24,
+        ("jmp", [Mem { .. }]) => 18,
+        ("jmp", _) => 15,
+        ("jcxz", _) => branch(18, 6),
+        (mnemonic, _) if mnemonic.starts_with('j') => branch(16, 4),
+        ("loop", _) => branch(17, 5),
+        ("loopz", _) => branch(18, 6),
+        ("loopnz", _) => branch(19, 5),
+        ("call", [Far(..)]) => 28,
+        ("call", [Reg(..)]) => 16,
+        ("call", [Mem { .. }]) if instruction.far => 37,
+        ("call", [Mem { .. }]) => 21,
+        ("call", _) => 19,
+        ("ret", []) => 8,
+        ("ret", _) => 12,
+        ("retf", []) => 18,
+        ("retf", _) => 17,
+        // A repeat takes 9 clocks and then a number of clocks for every element
+        (mnemonic, []) if instruction.is_string_op() => {
+            let (single, repeated) = match &mnemonic[..4] {
+                "movs" => (18, 17),
+                "cmps" => (22, 22),
+                "scas" => (15, 15),
+                "lods" => (12, 13),
+                _ => (11, 10),
+            };
+            match instruction.prefixes.rep {
+                Some(_) => 9 + repeated * cycles.repeat,
+                None => single,
+            }
+        }
+        ("push", [Reg(..)]) => 11,
+        ("push", [SegReg(_)]) => 10,
+        ("push", _) => 16,
+        ("pop", [Reg(..) | SegReg(_)]) => 8,
+        ("pop", _) => 17,
+        ("pushf", _) => 10,
+        ("popf", _) => 8,
+        ("int", _) => 51,
+        ("int3", _) => 52,
+        ("into", _) => branch(53, 4),
+        ("iret", _) => 24,
+        ("xlat", _) => 11,
+        ("lahf" | "sahf", _) => 4,
+        ("in" | "out", [_, Imm(_)] | [Imm(_), _]) => 10,
+        ("in" | "out", _) => 8,
+        ("hlt" | "clc" | "stc" | "cmc" | "cld" | "std" | "cli" | "sti", _) => 2,
+        _ => panic!("There are no clocks for {mnemonic}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CpuMode, decode_before};
+
+    fn clocks(bytes: &[u8]) -> Clocks {
+        let instruction = decode_before(bytes, CpuMode::I8086);
+        estimate(&instruction, &Cycles::default(), false)
+    }
+
+    #[test]
+    fn accumulator_moves_compute_no_effective_address() {
+        // mov ax, [4096] in the short form and with a ModR/M byte
+        let short = clocks(&[0xA1, 0x00, 0x10]);
+        assert_eq!((short.base, short.effective_address), (10, 0));
+        let modrm = clocks(&[0x8B, 0x06, 0x00, 0x10]);
+        assert_eq!((modrm.base, modrm.effective_address), (8, 6));
+        // mov [4096], al with a segment override
+        let es = clocks(&[0x26, 0xA2, 0x00, 0x10]);
+        assert_eq!(es.total(), 12);
+    }
+
+    #[test]
+    fn an_encoded_zero_displacement_counts() {
+        // mov ax, [di] and mov ax, [di + 0]
+        assert_eq!(clocks(&[0x8B, 0x05]).effective_address, 5);
+        assert_eq!(clocks(&[0x8B, 0x45, 0x00]).effective_address, 9);
+    }
+}
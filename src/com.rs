@@ -0,0 +1,44 @@
+// DOS .COM programs: loaded at offset 0x100, right behind the Program Segment Prefix.
+
+pub const ORIGIN: u32 = 0x100;
+// Where the simulator loads a .COM file unless --origin gives a segment
+pub const LOAD_SEGMENT: u16 = 0x1000;
+// DOS starts a .COM program with the stack at the top of its segment and a 0 word on it, so that
+// a ret goes to the int 20h at the start of the PSP
+pub const INITIAL_SP: u16 = 0xFFFE;
+
+// Start offset and description of each PSP field, a field runs until the next one starts
+const PSP_FIELDS: [(u16, &str); 21] = [
+    (0x00, "int 20h terminate"),
+    (0x02, "memory size in paragraphs"),
+    (0x04, "reserved"),
+    (0x05, "far call to DOS"),
+    (0x0A, "terminate address"),
+    (0x0E, "ctrl-break address"),
+    (0x12, "critical error address"),
+    (0x16, "parent PSP segment"),
+    (0x18, "job file table"),
+    (0x2C, "environment segment"),
+    (0x2E, "SS:SP on last int 21h"),
+    (0x32, "job file table size"),
+    (0x34, "job file table pointer"),
+    (0x38, "previous PSP"),
+    (0x3C, "reserved"),
+    (0x50, "int 21h, retf"),
+    (0x53, "reserved"),
+    (0x5C, "first FCB"),
+    (0x6C, "second FCB"),
+    (0x80, "command tail length"),
+    (0x81, "command tail"),
+];
+
+pub fn psp_field(offset: u16) -> Option<&'static str> {
+    if offset as u32 >= ORIGIN {
+        return None;
+    }
+    PSP_FIELDS
+        .iter()
+        .rev()
+        .find(|(start, _)| *start <= offset)
+        .map(|(_, name)| *name)
+}
@@ -0,0 +1,821 @@
+// The 8086 register file and the execution of decoded instructions.
+
+use crate::flags::{self, CF, DF, IF, OF, PF, SF, TF, ZF};
+use crate::interrupts::InterruptHandler;
+use crate::memory::Memory;
+use crate::{EffectiveAddress, Instruction, Operand, REGISTER_MAP, REP_PREFIX, SEGMENT_REGS};
+
+// Indices into REGISTER_MAP and Cpu::registers
+pub const AX: usize = 0;
+const CX: usize = 1;
+pub const DX: usize = 2;
+const BX: usize = 3;
+pub const SP: usize = 4;
+const BP: usize = 5;
+const SI: usize = 6;
+const DI: usize = 7;
+// Indices into SEGMENT_REGS and Cpu::segments
+const ES: usize = 0;
+pub const CS: usize = 1;
+const SS: usize = 2;
+pub const DS: usize = 3;
+
+// Base and index register of each entry of EFFECTIVE_ADDRESS_BASES
+const ADDRESS_REGISTERS: [(usize, Option<usize>); 8] = [
+    (BX, Some(SI)),
+    (BX, Some(DI)),
+    (BP, Some(SI)),
+    (BP, Some(DI)),
+    (SI, None),
+    (DI, None),
+    (BP, None),
+    (BX, None),
+];
+
+// The order the reference listings print the general registers in
+const PRINT_ORDER: [usize; 8] = [0, 3, 1, 2, 4, 5, 6, 7];
+// The flags lahf and sahf copy, SF, ZF, AF, PF and CF
+const LOW_FLAGS: u16 = 0xD5;
+// The bits of FLAGS that hold a flag. Of the others the 8086 reads bit 1 and bits 12-15 as 1.
+const DEFINED_FLAGS: u16 = 0x0FD5;
+const FIXED_FLAGS: u16 = 0xF002;
+
+// What the last instruction did that its clocks depend on
+#[derive(Clone, Copy, Default)]
+pub struct Cycles {
+    // Words read or written in memory and how many of them were at an odd address
+    pub word_transfers: u32,
+    pub odd_transfers: u32,
+    // A conditional jump or a loop went to its target
+    pub taken: bool,
+    // The single-step interrupt followed the instruction
+    pub trapped: bool,
+    // Bits shifted by cl, or elements a repeated string instruction went through
+    pub repeat: u32,
+}
+
+// A change an instruction makes, for the trace
+#[derive(Clone)]
+pub enum Change {
+    // Index into REGISTER_MAP
+    Register(usize, u16, u16),
+    Segment(usize, u16, u16),
+    Memory {
+        seg_idx: usize,
+        offset: u16,
+        width: usize,
+        old: u16,
+        new: u16,
+    },
+    Ip(u16, u16),
+    Flags(u16, u16),
+}
+
+#[derive(Clone, Default)]
+pub struct Cpu {
+    // ax, cx, dx, bx, sp, bp, si, di, in the order of REGISTER_MAP
+    pub registers: [u16; 8],
+    // es, cs, ss, ds
+    pub segments: [u16; 4],
+    pub ip: u16,
+    pub flags: u16,
+    // Set by hlt
+    pub halted: bool,
+    // Mnemonic of an instruction the simulator cannot execute, which stops the run
+    pub unsupported: Option<&'static str>,
+    pub cycles: Cycles,
+    // Memory the last instruction wrote, as Change::Memory
+    writes: Vec<Change>,
+    // Interrupt type the instruction being executed raises
+    raised: Option<u8>,
+    // Resume an interrupted repeated string instruction at its last prefix, as the 8086 does
+    pub rep_resume_bug: bool,
+    // Segment override prefix of the instruction being executed
+    segment_override: Option<usize>,
+}
+
+impl Cpu {
+    // Every segment register points at the segment the program is loaded into
+    pub fn new(segment: u16, ip: u16) -> Self {
+        Cpu {
+            segments: [segment; 4],
+            ip,
+            ..Cpu::default()
+        }
+    }
+
+    // Byte registers 0-3 are the low halves of ax-bx, 4-7 the high halves
+    pub fn register(&self, reg_idx: usize, width: usize) -> u16 {
+        match width {
+            0 if reg_idx < 4 => self.registers[reg_idx] & 0xFF,
+            0 => self.registers[reg_idx - 4] >> 8,
+            1 => self.registers[reg_idx],
+            _ => panic!("The simulator has no 32-bit registers"),
+        }
+    }
+
+    pub fn set_register(&mut self, reg_idx: usize, width: usize, value: u16) {
+        match width {
+            0 if reg_idx < 4 => {
+                let register = &mut self.registers[reg_idx];
+                *register = *register & 0xFF00 | value & 0xFF;
+            }
+            0 => {
+                let register = &mut self.registers[reg_idx - 4];
+                *register = *register & 0x00FF | value << 8;
+            }
+            1 => self.registers[reg_idx] = value,
+            _ => panic!("The simulator has no 32-bit registers"),
+        }
+    }
+
+    // Segment register index and offset of a memory operand. Addresses based on BP are in the
+    // stack segment, all others in the data segment, unless the instruction overrides it.
+    pub fn address(&self, address: &EffectiveAddress) -> (usize, u16) {
+        let (segment, offset) = match *address {
+            EffectiveAddress::Direct(offset) => (DS, offset),
+            EffectiveAddress::Indirect(base_idx, disp, _) => {
+                let (base, index) = ADDRESS_REGISTERS[base_idx];
+                let index = index.map_or(0, |index| self.registers[index]);
+                let offset = self.registers[base]
+                    .wrapping_add(index)
+                    .wrapping_add(disp.unwrap_or(0) as u16);
+                (if base == BP { SS } else { DS }, offset)
+            }
+            EffectiveAddress::Reg(_) => unreachable!("mod=0b11 is decoded as a register operand"),
+            EffectiveAddress::Indirect32 { .. } => {
+                panic!("32-bit addresses are not supported by the simulator")
+            }
+        };
+        (self.segment_override.unwrap_or(segment), offset)
+    }
+
+    // IP already points behind the instruction. With TF set the instruction is followed by the
+    // single-step interrupt, unless it started an interrupt through the vector table, which
+    // clears TF.
+    pub fn execute(
+        &mut self,
+        instruction: &Instruction,
+        memory: &mut Memory,
+        handler: &mut dyn InterruptHandler,
+    ) {
+        self.segment_override = instruction.prefixes.segment.map(segment_index);
+        self.cycles = Cycles::default();
+        self.writes.clear();
+        let trap = self.flags & TF != 0;
+        self.operation(instruction, memory);
+        if let Some(interrupt_type) = self.raised.take() {
+            self.interrupt(memory, handler, interrupt_type);
+        }
+        if trap && self.flags & TF != 0 {
+            self.interrupt(memory, handler, 1);
+            self.cycles.trapped = true;
+        }
+    }
+
+    fn operation(&mut self, instruction: &Instruction, memory: &mut Memory) {
+        let operands = &instruction.operands[..];
+        match (instruction.mnemonic, operands) {
+            ("mov", [dst, src]) => {
+                let value = self.read(memory, src);
+                self.write(memory, dst, value);
+            }
+            ("xchg", [first, second]) => {
+                let (a, b) = (self.read(memory, first), self.read(memory, second));
+                self.write(memory, first, b);
+                self.write(memory, second, a);
+            }
+            ("lea", [dst, Operand::Mem { address, .. }]) => {
+                let (_, offset) = self.address(address);
+                self.write(memory, dst, offset);
+            }
+            // The offset is loaded into the register and the segment behind it into ds or es
+            ("lds" | "les", [dst, Operand::Mem { address, .. }]) => {
+                let (segment, offset) = self.address(address);
+                let segment = self.segments[segment];
+                let pointer_offset = self.load(memory, segment, offset, 1);
+                let pointer_segment = self.load(memory, segment, offset.wrapping_add(2), 1);
+                self.write(memory, dst, pointer_offset);
+                let seg_idx = if instruction.mnemonic == "lds" { DS } else { ES };
+                self.segments[seg_idx] = pointer_segment;
+            }
+            // Relative targets count from IP, which already points behind the instruction
+            (mnemonic, [Operand::Rel(disp, _)]) if mnemonic.starts_with('j') => {
+                if mnemonic == "jmp" || self.condition(mnemonic) {
+                    self.ip = self.ip.wrapping_add(*disp as u16);
+                    self.cycles.taken = true;
+                }
+            }
+            ("loop" | "loopz" | "loopnz", [Operand::Rel(disp, _)]) => {
+                let count = self.registers[CX].wrapping_sub(1);
+                self.registers[CX] = count;
+                let zero = self.flags & ZF != 0;
+                let taken = match instruction.mnemonic {
+                    "loopz" => zero,
+                    "loopnz" => !zero,
+                    _ => true,
+                };
+                if count != 0 && taken {
+                    self.ip = self.ip.wrapping_add(*disp as u16);
+                    self.cycles.taken = true;
+                }
+            }
+            ("call", [Operand::Rel(disp, _)]) => {
+                self.push(memory, self.ip);
+                self.ip = self.ip.wrapping_add(*disp as u16);
+            }
+            ("jmp" | "call", [Operand::Far(segment, offset)]) => {
+                if instruction.mnemonic == "call" {
+                    self.push(memory, self.segments[CS]);
+                    self.push(memory, self.ip);
+                }
+                self.segments[CS] = *segment;
+                self.ip = *offset as u16;
+            }
+            // Through a register or a word in memory, far ones take the segment from the word
+            // behind the offset
+            ("jmp" | "call", [target]) => {
+                let offset = self.read(memory, target);
+                let segment = match target {
+                    Operand::Mem { address, .. } if instruction.far => {
+                        let (segment, offset) = self.address(address);
+                        Some(self.load(memory, self.segments[segment], offset.wrapping_add(2), 1))
+                    }
+                    _ => None,
+                };
+                if instruction.mnemonic == "call" {
+                    if segment.is_some() {
+                        self.push(memory, self.segments[CS]);
+                    }
+                    self.push(memory, self.ip);
+                }
+                if let Some(segment) = segment {
+                    self.segments[CS] = segment;
+                }
+                self.ip = offset;
+            }
+            // The immediate is the number of parameter bytes to drop
+            ("ret" | "retf", operands) => {
+                self.ip = self.pop(memory);
+                if instruction.mnemonic == "retf" {
+                    self.segments[CS] = self.pop(memory);
+                }
+                if let [Operand::Imm(bytes)] = operands {
+                    self.registers[SP] = self.registers[SP].wrapping_add(*bytes as u16);
+                }
+            }
+            // sp is decremented before the 8086 reads it, so push sp stores the new value
+            ("push", [Operand::Reg(SP, 1)]) => {
+                let value = self.registers[SP].wrapping_sub(2);
+                self.push(memory, value);
+            }
+            ("push", [operand]) => {
+                let value = self.read(memory, operand);
+                self.push(memory, value);
+            }
+            ("pop", [operand]) => {
+                let value = self.pop(memory);
+                self.write(memory, operand, value);
+            }
+            ("pushf", []) => self.push(memory, self.flags | FIXED_FLAGS),
+            ("popf", []) => self.flags = self.pop(memory) & DEFINED_FLAGS,
+            ("int", [Operand::Imm(interrupt_type)]) => {
+                self.raised = Some(*interrupt_type as u8)
+            }
+            ("int3", []) => self.raised = Some(3),
+            ("into", []) => {
+                if self.flags & OF != 0 {
+                    self.raised = Some(4);
+                    self.cycles.taken = true;
+                }
+            }
+            ("iret", []) => {
+                self.ip = self.pop(memory);
+                self.segments[CS] = self.pop(memory);
+                self.flags = self.pop(memory) & DEFINED_FLAGS;
+            }
+            (_, []) if instruction.is_string_op() => self.string(instruction, memory),
+            ("hlt", []) => self.halted = true,
+            ("xlat", []) => {
+                let segment = self.segments[self.segment_override.unwrap_or(DS)];
+                let offset = self.registers[BX].wrapping_add(self.register(AX, 0));
+                self.set_register(AX, 0, memory.read_u8(segment, offset) as u16);
+            }
+            ("add" | "or" | "adc" | "sbb" | "and" | "sub" | "xor" | "cmp" | "test", [dst, src]) => {
+                let (result, flags) = flags::alu(
+                    instruction.mnemonic,
+                    operand_width(dst),
+                    self.read(memory, dst),
+                    self.read(memory, src),
+                    self.flags,
+                );
+                self.flags = flags;
+                if !matches!(instruction.mnemonic, "cmp" | "test") {
+                    self.write(memory, dst, result);
+                }
+            }
+            ("rol" | "ror" | "rcl" | "rcr" | "shl" | "shr" | "sar", [dst, count_operand]) => {
+                let count = self.read(memory, count_operand) as u8;
+                if let Operand::Reg(..) = count_operand {
+                    self.cycles.repeat = count as u32;
+                }
+                let (result, flags) = flags::shift(
+                    instruction.mnemonic,
+                    operand_width(dst),
+                    self.read(memory, dst),
+                    count,
+                    self.flags,
+                );
+                self.flags = flags;
+                self.write(memory, dst, result);
+            }
+            ("inc" | "dec", [operand]) => {
+                let (result, flags) = flags::inc_dec(
+                    instruction.mnemonic,
+                    operand_width(operand),
+                    self.read(memory, operand),
+                    self.flags,
+                );
+                self.flags = flags;
+                self.write(memory, operand, result);
+            }
+            // Subtracting from 0 sets CF unless the operand is 0
+            ("neg", [operand]) => {
+                let (result, flags) =
+                    flags::alu("sub", operand_width(operand), 0, self.read(memory, operand), self.flags);
+                self.flags = flags;
+                self.write(memory, operand, result);
+            }
+            // The product goes to AX, or DX:AX for words
+            ("mul" | "imul", [operand]) => {
+                let width = operand_width(operand);
+                let (product, flags) = flags::multiply(
+                    instruction.mnemonic,
+                    width,
+                    self.register(AX, width),
+                    self.read(memory, operand),
+                    self.flags,
+                );
+                self.flags = flags;
+                self.registers[AX] = product as u16;
+                if width == 1 {
+                    self.registers[DX] = (product >> 16) as u16;
+                }
+            }
+            // AX, or DX:AX for words, divided into the quotient in AL or AX and the remainder in AH
+            // or DX. The flags are left alone. A divisor of 0 or a quotient that does not fit raises
+            // the divide error, and the 8086 pushes the IP of the next instruction for it.
+            // The flags are undefined afterwards and deliberately kept as they were
+            ("div" | "idiv", [operand]) => {
+                let width = operand_width(operand);
+                let dividend = if width == 0 {
+                    self.registers[AX] as u32
+                } else {
+                    (self.registers[DX] as u32) << 16 | self.registers[AX] as u32
+                };
+                let divisor = self.read(memory, operand);
+                match divide(instruction.mnemonic, width, dividend, divisor) {
+                    Some((quotient, remainder)) if width == 0 => {
+                        self.registers[AX] = remainder << 8 | quotient;
+                    }
+                    Some((quotient, remainder)) => {
+                        self.registers[AX] = quotient;
+                        self.registers[DX] = remainder;
+                    }
+                    None => self.raised = Some(0),
+                }
+            }
+            ("daa" | "das" | "aaa" | "aas", []) => {
+                let (ax, flags) =
+                    flags::decimal_adjust(instruction.mnemonic, self.registers[AX], self.flags);
+                self.registers[AX] = ax;
+                self.flags = flags;
+            }
+            // The base is 10 unless the instruction gives another one
+            ("aam" | "aad", operands) => {
+                let base = match operands {
+                    [Operand::Imm(base)] => *base as u8,
+                    _ => 10,
+                };
+                let ax = self.registers[AX];
+                let adjusted = if instruction.mnemonic == "aam" {
+                    flags::aam(ax, base, self.flags)
+                } else {
+                    Some(flags::aad(ax, base, self.flags))
+                };
+                match adjusted {
+                    Some((ax, flags)) => {
+                        self.registers[AX] = ax;
+                        self.flags = flags;
+                    }
+                    None => self.raised = Some(0),
+                }
+            }
+            ("cbw", []) => self.registers[AX] = self.register(AX, 0) as u8 as i8 as u16,
+            ("cwd", []) => {
+                self.registers[DX] = if self.registers[AX] & 0x8000 != 0 { 0xFFFF } else { 0 };
+            }
+            ("not", [operand]) => {
+                let value = self.read(memory, operand);
+                self.write(memory, operand, !value);
+            }
+            ("clc", []) => self.flags &= !CF,
+            ("stc", []) => self.flags |= CF,
+            ("cmc", []) => self.flags ^= CF,
+            ("cld", []) => self.flags &= !DF,
+            ("std", []) => self.flags |= DF,
+            ("cli", []) => self.flags &= !IF,
+            ("sti", []) => self.flags |= IF,
+            // Bit 1 reads as 1, as in the FLAGS pushf pushes
+            ("lahf", []) => self.set_register(4, 0, self.flags & LOW_FLAGS | FIXED_FLAGS & 0xFF),
+            ("sahf", []) => {
+                self.flags = self.flags & !LOW_FLAGS | self.register(4, 0) & LOW_FLAGS;
+            }
+            // No devices are attached: in reads all ones, as from an unused port, and out is lost
+            ("in", [dst, _]) => self.write(memory, dst, 0xFFFF),
+            ("out", [_, _]) => {}
+            _ => self.unsupported = Some(instruction.mnemonic),
+        }
+    }
+
+    // Without a rep prefix the instruction handles one element. With one it repeats while CX is
+    // not 0, counting CX down, and cmps and scas also stop when ZF is not what repe or repne
+    // wants. The single-step interrupt comes between two elements, with IP back on the
+    // instruction so the handler returns to the rest of the repeat.
+    fn string(&mut self, instruction: &Instruction, memory: &mut Memory) {
+        let Some(rep) = instruction.prefixes.rep else {
+            self.string_element(instruction, memory);
+            return;
+        };
+        while self.registers[CX] != 0 {
+            self.string_element(instruction, memory);
+            self.cycles.repeat += 1;
+            self.registers[CX] -= 1;
+            let zero = self.flags & ZF != 0;
+            if instruction.compares_strings() && zero != (rep == REP_PREFIX) {
+                break;
+            }
+            if self.flags & TF != 0 && self.registers[CX] != 0 {
+                self.ip = self.ip.wrapping_sub(instruction.size as u16);
+                // The 8086 only backs up to the last prefix, the ones in front of it are lost
+                if self.rep_resume_bug {
+                    let skipped = instruction.prefixes.count().saturating_sub(1);
+                    self.ip = self.ip.wrapping_add(skipped as u16);
+                }
+                break;
+            }
+        }
+    }
+
+    // The source is DS:SI, or another segment with an override, the destination always ES:DI.
+    // SI and DI step by the element size, down when DF is set.
+    fn string_element(&mut self, instruction: &Instruction, memory: &mut Memory) {
+        let width = if instruction.mnemonic.ends_with('b') { 0 } else { 1 };
+        let step = if self.flags & DF != 0 {
+            (width as u16 + 1).wrapping_neg()
+        } else {
+            width as u16 + 1
+        };
+        let source = self.segments[self.segment_override.unwrap_or(DS)];
+        let (si, di) = (self.registers[SI], self.registers[DI]);
+        let (uses_si, uses_di) = match &instruction.mnemonic[..4] {
+            "movs" => {
+                let value = self.load(memory, source, si, width);
+                self.store(memory, ES, di, width, value);
+                (true, true)
+            }
+            "cmps" => {
+                let first = self.load(memory, source, si, width);
+                let second = self.load(memory, self.segments[ES], di, width);
+                self.flags = flags::alu("cmp", width, first, second, self.flags).1;
+                (true, true)
+            }
+            "scas" => {
+                let value = self.load(memory, self.segments[ES], di, width);
+                self.flags = flags::alu("cmp", width, self.register(AX, width), value, self.flags).1;
+                (false, true)
+            }
+            "lods" => {
+                let value = self.load(memory, source, si, width);
+                self.set_register(AX, width, value);
+                (true, false)
+            }
+            _ => {
+                let value = self.register(AX, width);
+                self.store(memory, ES, di, width, value);
+                (false, true)
+            }
+        };
+        if uses_si {
+            self.registers[SI] = si.wrapping_add(step);
+        }
+        if uses_di {
+            self.registers[DI] = di.wrapping_add(step);
+        }
+    }
+
+    // The handler gets the interrupt first. Otherwise FLAGS, CS and IP are pushed and execution
+    // continues at the vector of the interrupt type in the table at 0000:0000, with IF and TF
+    // cleared.
+    fn interrupt(
+        &mut self,
+        memory: &mut Memory,
+        handler: &mut dyn InterruptHandler,
+        interrupt_type: u8,
+    ) {
+        if handler.interrupt(interrupt_type, self, memory) {
+            return;
+        }
+        self.push(memory, self.flags | FIXED_FLAGS);
+        self.flags &= !(IF | TF);
+        self.push(memory, self.segments[CS]);
+        self.push(memory, self.ip);
+        let vector = interrupt_type as u16 * 4;
+        self.ip = self.load(memory, 0, vector, 1);
+        self.segments[CS] = self.load(memory, 0, vector + 2, 1);
+    }
+
+    fn push(&mut self, memory: &mut Memory, value: u16) {
+        self.registers[SP] = self.registers[SP].wrapping_sub(2);
+        self.store(memory, SS, self.registers[SP], 1, value);
+    }
+
+    fn pop(&mut self, memory: &Memory) -> u16 {
+        let value = self.load(memory, self.segments[SS], self.registers[SP], 1);
+        self.registers[SP] = self.registers[SP].wrapping_add(2);
+        value
+    }
+
+    // Whether a conditional jump or jcxz is taken
+    fn condition(&self, mnemonic: &str) -> bool {
+        let flag = |bit: u16| self.flags & bit != 0;
+        let less = flag(SF) != flag(OF);
+        match mnemonic {
+            "jo" => flag(OF),
+            "jno" => !flag(OF),
+            "jb" => flag(CF),
+            "jnb" => !flag(CF),
+            "je" => flag(ZF),
+            "jne" => !flag(ZF),
+            "jbe" => flag(CF) || flag(ZF),
+            "ja" => !flag(CF) && !flag(ZF),
+            "js" => flag(SF),
+            "jns" => !flag(SF),
+            "jp" => flag(PF),
+            "jnp" => !flag(PF),
+            "jl" => less,
+            "jge" => !less,
+            "jle" => less || flag(ZF),
+            "jg" => !less && !flag(ZF),
+            "jcxz" => self.registers[CX] == 0,
+            _ => unreachable!("{mnemonic} is not a conditional jump"),
+        }
+    }
+
+    fn read(&mut self, memory: &Memory, operand: &Operand) -> u16 {
+        match *operand {
+            Operand::Reg(reg_idx, width) => self.register(reg_idx, width),
+            Operand::SegReg(seg_idx) => self.segments[segment_index(seg_idx)],
+            Operand::Mem {
+                ref address, width, ..
+            } => {
+                let (segment, offset) = self.address(address);
+                self.load(memory, self.segments[segment], offset, width)
+            }
+            // Immediates are sign extended, the destination keeps the bits it is wide
+            Operand::Imm(immediate) => immediate as u16,
+            _ => panic!("Jump targets cannot be read as values"),
+        }
+    }
+
+    fn write(&mut self, memory: &mut Memory, operand: &Operand, value: u16) {
+        match *operand {
+            Operand::Reg(reg_idx, width) => self.set_register(reg_idx, width, value),
+            Operand::SegReg(seg_idx) => self.segments[segment_index(seg_idx)] = value,
+            Operand::Mem {
+                ref address, width, ..
+            } => {
+                let (segment, offset) = self.address(address);
+                self.store(memory, segment, offset, width, value);
+            }
+            _ => panic!("Only registers and memory can be written"),
+        }
+    }
+
+    // Memory accesses that count their word transfers for the clocks
+    fn load(&mut self, memory: &Memory, segment: u16, offset: u16, width: usize) -> u16 {
+        self.count_transfer(offset, width);
+        memory.read(segment, offset, width)
+    }
+
+    // Stores go through a segment register, the trace names it
+    fn store(&mut self, memory: &mut Memory, seg_idx: usize, offset: u16, width: usize, value: u16) {
+        self.count_transfer(offset, width);
+        let segment = self.segments[seg_idx];
+        self.writes.push(Change::Memory {
+            seg_idx,
+            offset,
+            width,
+            old: memory.read(segment, offset, width),
+            new: value,
+        });
+        memory.write(segment, offset, width, value);
+    }
+
+    // Segments start at multiples of 16, so the offset tells whether the address is odd
+    fn count_transfer(&mut self, offset: u16, width: usize) {
+        if width != 0 {
+            self.cycles.word_transfers += 1;
+            self.cycles.odd_transfers += (offset & 1) as u32;
+        }
+    }
+
+    // What the last instruction changed, against the state before it: the registers, the memory
+    // it wrote, IP and FLAGS
+    pub fn changes(&self, before: &Cpu) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (reg_idx, (&old, &new)) in before.registers.iter().zip(&self.registers).enumerate() {
+            if old != new {
+                changes.push(Change::Register(reg_idx, old, new));
+            }
+        }
+        for (seg_idx, (&old, &new)) in before.segments.iter().zip(&self.segments).enumerate() {
+            if old != new {
+                changes.push(Change::Segment(seg_idx, old, new));
+            }
+        }
+        changes.extend(self.writes.iter().cloned());
+        if before.ip != self.ip {
+            changes.push(Change::Ip(before.ip, self.ip));
+        }
+        if before.flags != self.flags {
+            changes.push(Change::Flags(before.flags, self.flags));
+        }
+        changes
+    }
+
+    // In the format of the Computer Enhance reference listings, registers that are 0 are left out
+    pub fn print_registers(&self) {
+        println!("Final registers:");
+        for reg_idx in PRINT_ORDER {
+            print_register(REGISTER_MAP[reg_idx][1], self.registers[reg_idx]);
+        }
+        for (seg_idx, &value) in self.segments.iter().enumerate() {
+            print_register(SEGMENT_REGS[seg_idx], value);
+        }
+        print_register("ip", self.ip);
+        if self.flags != 0 {
+            println!("   flags: {}", flags::letters(self.flags));
+        }
+    }
+}
+
+fn print_register(name: &str, value: u16) {
+    if value != 0 {
+        println!("      {name}: {value:#06x} ({value})");
+    }
+}
+
+fn operand_width(operand: &Operand) -> usize {
+    match *operand {
+        Operand::Reg(_, width) | Operand::Mem { width, .. } => width,
+        _ => 1,
+    }
+}
+
+// Quotient and remainder of div or idiv, None when the divisor is 0 or the quotient does not fit.
+// The remainder of idiv has the sign of the dividend, and the 8086 rejects the most negative
+// quotient, -128 or -32768.
+fn divide(mnemonic: &str, width: usize, dividend: u32, divisor: u16) -> Option<(u16, u16)> {
+    let (mask, limit) = if width == 0 {
+        (0xFF, 0x7F)
+    } else {
+        (0xFFFF, 0x7FFF)
+    };
+    if mnemonic == "div" {
+        let divisor = (divisor & mask) as u32;
+        let quotient = dividend.checked_div(divisor)?;
+        return (quotient <= mask as u32).then_some((quotient as u16, (dividend % divisor) as u16));
+    }
+
+    let (dividend, divisor) = if width == 0 {
+        (dividend as u16 as i16 as i64, divisor as u8 as i8 as i64)
+    } else {
+        (dividend as i32 as i64, divisor as i16 as i64)
+    };
+    let quotient = dividend.checked_div(divisor)?;
+    let remainder = dividend % divisor;
+    (quotient.abs() <= limit).then_some((quotient as u16 & mask, remainder as u16 & mask))
+}
+
+fn segment_index(seg_idx: usize) -> usize {
+    if seg_idx > DS {
+        panic!("The simulator has no fs and gs");
+    }
+    seg_idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::VectorTable;
+    use crate::{CpuMode, decode_instruction};
+
+    // Runs the code at 0000:0100 the way the simulator does, until it halts or leaves the code
+    fn run(code: &[u8]) -> (Cpu, Memory) {
+        let mut memory = Memory::new();
+        memory.load(0, 0x100, code);
+        // The divide error handler
+        memory.write_u16(0, 0, 0x0300);
+        let mut cpu = Cpu::new(0, 0x100);
+        cpu.registers[SP] = 0x1000;
+        let end = 0x100 + code.len() as u16;
+        while !cpu.halted && cpu.unsupported.is_none() && (0x100..end).contains(&cpu.ip) {
+            let instruction =
+                decode_instruction(&memory.fetch(cpu.segments[CS], cpu.ip), CpuMode::I8086);
+            cpu.ip = cpu.ip.wrapping_add(instruction.size as u16);
+            cpu.execute(&instruction, &mut memory, &mut VectorTable);
+        }
+        (cpu, memory)
+    }
+
+    #[test]
+    fn div_and_idiv() {
+        assert_eq!(divide("div", 0, 0x0107, 0x10), Some((0x10, 0x07)));
+        assert_eq!(divide("div", 0, 0x0100, 0x01), None);
+        assert_eq!(divide("div", 1, 0x0001_0000, 0x0002), Some((0x8000, 0)));
+        assert_eq!(divide("div", 1, 0x0001_0000, 0), None);
+        // -7 / 2, the remainder has the sign of the dividend
+        assert_eq!(divide("idiv", 0, 0xFFF9, 0x02), Some((0xFD, 0xFF)));
+        assert_eq!(divide("idiv", 0, 0xFF02, 0x02), Some((0x81, 0)));
+        assert_eq!(divide("idiv", 0, 0x00FE, 0x02), Some((0x7F, 0)));
+        assert_eq!(divide("idiv", 0, 0x0100, 0x02), None);
+    }
+
+    #[test]
+    fn idiv_rejects_the_most_negative_quotient() {
+        // -256 / 2 and -65536 / 2
+        assert_eq!(divide("idiv", 0, 0xFF00, 0x02), None);
+        assert_eq!(divide("idiv", 1, 0xFFFF_0000, 0x0002), None);
+        assert_eq!(divide("idiv", 1, 0xFFFF_0002, 0x0002), Some((0x8001, 0)));
+    }
+
+    #[test]
+    fn divide_error_pushes_the_next_instruction() {
+        // mov bl, 0 / div bl / hlt
+        let (cpu, memory) = run(&[0xB3, 0x00, 0xF6, 0xF3, 0xF4]);
+        assert_eq!(cpu.ip, 0x0300);
+        assert_eq!(cpu.registers[SP], 0x0FFA);
+        assert_eq!(memory.read_u16(0, 0x0FFA), 0x0104);
+        assert_eq!(memory.read_u16(0, 0x0FFC), 0);
+    }
+
+    #[test]
+    fn aam_0_raises_a_divide_error() {
+        // mov ax, 0x25 / aam 0 / hlt
+        let (cpu, memory) = run(&[0xB8, 0x25, 0x00, 0xD4, 0x00, 0xF4]);
+        assert_eq!(cpu.ip, 0x0300);
+        assert_eq!(cpu.registers[AX], 0x0025);
+        assert_eq!(memory.read_u16(0, 0x0FFA), 0x0105);
+    }
+
+    #[test]
+    fn word_multiply_and_divide_use_dx() {
+        // mov ax, 0x1234 / mov bx, 0x100 / mul bx / div bx / hlt
+        let (cpu, _) = run(&[
+            0xB8, 0x34, 0x12, 0xBB, 0x00, 0x01, 0xF7, 0xE3, 0xF7, 0xF3, 0xF4,
+        ]);
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers[AX], 0x1234);
+        assert_eq!(cpu.registers[DX], 0);
+        assert_eq!(cpu.flags & (CF | OF), CF | OF);
+    }
+
+    #[test]
+    fn lahf_sets_bit_1_like_pushf() {
+        // stc / lahf / pushf / pop bx / hlt
+        let (cpu, _) = run(&[0xF9, 0x9F, 0x9C, 0x5B, 0xF4]);
+        assert_eq!(cpu.register(4, 0), 0x03);
+        assert_eq!(cpu.registers[BX] & 0xFF, 0x03);
+    }
+
+    #[test]
+    fn div_and_idiv_keep_the_flags() {
+        // stc / mov ax, 0x25 / mov bl, 10 / div bl / mov ax, -37 / idiv bl / hlt
+        let (cpu, _) = run(&[
+            0xF9, 0xB8, 0x25, 0x00, 0xB3, 0x0A, 0xF6, 0xF3, 0xB8, 0xDB, 0xFF, 0xF6, 0xFB, 0xF4,
+        ]);
+        assert!(cpu.halted);
+        assert_eq!(cpu.registers[AX], 0xF9FD);
+        assert_eq!(cpu.flags, CF);
+    }
+
+    #[test]
+    fn ports_read_all_ones_and_unsupported_instructions_stop() {
+        // mov al, 5 / out 0x60, al / in al, 0x60 / in ax, dx / wait / hlt
+        let (cpu, _) = run(&[0xB0, 0x05, 0xE6, 0x60, 0xE4, 0x60, 0xED, 0x9B, 0xF4]);
+        assert_eq!(cpu.registers[AX], 0xFFFF);
+        assert_eq!(cpu.unsupported, Some("wait"));
+        assert!(!cpu.halted);
+    }
+}
@@ -0,0 +1,236 @@
+// User-declared data ranges: tables and messages written as db/dw/dd instead of decoded.
+
+use std::fmt::Write;
+
+use crate::format::write_db;
+use crate::parse_number;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DataKind {
+    Bytes,
+    Words,
+    Dwords,
+    // Near pointers, one word per line
+    Pointers,
+    // offset:segment pairs
+    FarPointers,
+    Strings,
+}
+
+#[derive(Clone, Copy)]
+pub struct DataRange {
+    pub start: usize,
+    pub end: usize,
+    pub kind: DataKind,
+}
+
+const BYTES_PER_LINE: usize = 16;
+
+// start:end[:type] with an exclusive end, numbers as accepted by parse_number
+pub fn parse_range(text: &str) -> Option<DataRange> {
+    let mut parts = text.split(':');
+    let start = parse_number(parts.next()?)? as usize;
+    let end = parse_number(parts.next()?)? as usize;
+    let kind = match parts.next() {
+        None => DataKind::Bytes,
+        Some(name) => parse_kind(name)?,
+    };
+    if parts.next().is_some() || start >= end {
+        return None;
+    }
+
+    Some(DataRange { start, end, kind })
+}
+
+pub fn parse_kind(name: &str) -> Option<DataKind> {
+    match name {
+        "db" => Some(DataKind::Bytes),
+        "dw" => Some(DataKind::Words),
+        "dd" => Some(DataKind::Dwords),
+        "ptr" => Some(DataKind::Pointers),
+        "farptr" => Some(DataKind::FarPointers),
+        "str" => Some(DataKind::Strings),
+        _ => None,
+    }
+}
+
+// One range per line, in the --data syntax, with # starting a comment
+pub fn parse_range_file(text: &str) -> Vec<DataRange> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(line_idx, line)| {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                return None;
+            }
+            let range = parse_range(line);
+            Some(
+                range.unwrap_or_else(|| {
+                    panic!("Invalid data range on line {}: {line}", line_idx + 1)
+                }),
+            )
+        })
+        .collect()
+}
+
+// Moves file offsets into the coordinates of a slice starting at file offset base, ranges are
+// clipped to the slice
+pub fn relative_to(ranges: &[DataRange], base: usize) -> Vec<DataRange> {
+    let mut ranges: Vec<DataRange> = ranges
+        .iter()
+        .filter(|range| range.end > base)
+        .map(|range| DataRange {
+            start: range.start.saturating_sub(base),
+            end: range.end - base,
+            kind: range.kind,
+        })
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+// Covers 0..end with the sorted ranges and bytes in between
+pub fn fill_gaps(ranges: &[DataRange], end: usize) -> Vec<DataRange> {
+    let mut filled = Vec::new();
+    let mut offset = 0;
+    for range in ranges {
+        if offset < range.start {
+            filled.push(DataRange {
+                start: offset,
+                end: range.start,
+                kind: DataKind::Bytes,
+            });
+        }
+        filled.push(*range);
+        offset = offset.max(range.end);
+    }
+    if offset < end {
+        filled.push(DataRange {
+            start: offset,
+            end,
+            kind: DataKind::Bytes,
+        });
+    }
+    filled
+}
+
+pub fn print_data(bytes: &[u8], kind: DataKind) {
+    for line in data_lines(bytes, kind) {
+        println!("{line}");
+    }
+}
+
+pub fn data_lines(bytes: &[u8], kind: DataKind) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut print = |line: &mut String| lines.push(std::mem::take(line));
+
+    match kind {
+        DataKind::Bytes => {
+            for chunk in bytes.chunks(BYTES_PER_LINE) {
+                line.push_str("db ");
+                write_list(&mut line, chunk.iter());
+                print(&mut line);
+            }
+        }
+        DataKind::Words | DataKind::Dwords | DataKind::Pointers | DataKind::FarPointers => {
+            let (size, per_line) = match kind {
+                DataKind::Words => (2, 8),
+                DataKind::Dwords => (4, 4),
+                DataKind::Pointers => (2, 1),
+                _ => (4, 1),
+            };
+            // A trailing partial item stays as bytes
+            let rest = bytes.chunks_exact(size).remainder();
+            for group in bytes[..bytes.len() - rest.len()].chunks(size * per_line) {
+                if kind == DataKind::Dwords {
+                    line.push_str("dd ");
+                    write_list(
+                        &mut line,
+                        group.chunks_exact(4).map(|dword| {
+                            u32::from_le_bytes([dword[0], dword[1], dword[2], dword[3]])
+                        }),
+                    );
+                } else {
+                    line.push_str("dw ");
+                    write_list(
+                        &mut line,
+                        group
+                            .chunks_exact(2)
+                            .map(|word| u16::from_le_bytes([word[0], word[1]])),
+                    );
+                }
+                if kind == DataKind::FarPointers {
+                    let offset = u16::from_le_bytes([group[0], group[1]]);
+                    let segment = u16::from_le_bytes([group[2], group[3]]);
+                    write!(line, " ; {segment:04x}:{offset:04x}").unwrap();
+                }
+                print(&mut line);
+            }
+            if !rest.is_empty() {
+                line.push_str("db ");
+                write_list(&mut line, rest.iter());
+                print(&mut line);
+            }
+        }
+        DataKind::Strings => {
+            // A line per string, split after the usual terminators
+            for string in bytes.split_inclusive(|byte| matches!(byte, 0 | b'$' | b'\n')) {
+                write_db(&mut line, string);
+                print(&mut line);
+            }
+        }
+    }
+    lines
+}
+
+fn write_list<T: std::fmt::LowerHex>(buffer: &mut String, items: impl Iterator<Item = T>) {
+    for (idx, item) in items.enumerate() {
+        if idx > 0 {
+            buffer.push_str(", ");
+        }
+        write!(buffer, "{item:#x}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_parsed_and_written() {
+        let range = parse_range("0x10:0x16:dw").unwrap();
+        assert_eq!((range.start, range.end), (0x10, 0x16));
+        assert!(range.kind == DataKind::Words);
+        assert!(parse_range("16:16").is_none());
+        assert!(parse_range("0:4:dq").is_none());
+
+        // A word list with a trailing odd byte, a far pointer and strings
+        assert_eq!(
+            data_lines(&[0x34, 0x12, 0x78, 0x56, 0xFF], DataKind::Words),
+            ["dw 0x1234, 0x5678", "db 0xff"]
+        );
+        assert_eq!(
+            data_lines(&[0x00, 0x01, 0x00, 0xF0], DataKind::FarPointers),
+            ["dw 0x100, 0xf000 ; f000:0100"]
+        );
+        assert_eq!(data_lines(b"Hi$ok", DataKind::Strings).len(), 2);
+    }
+
+    #[test]
+    fn a_range_file_moves_with_the_slice() {
+        let ranges = parse_range_file("# messages\n0x20:0x30:str\n\n0x08:0x0C:dd # table\n");
+        assert_eq!(ranges.len(), 2);
+        let relative = relative_to(&ranges, 0x0A);
+        assert_eq!(
+            relative
+                .iter()
+                .map(|range| (range.start, range.end))
+                .collect::<Vec<_>>(),
+            [(0, 2), (0x16, 0x26)]
+        );
+        let filled = fill_gaps(&relative, 0x30);
+        assert_eq!(filled.len(), 4);
+        assert!(filled[1].kind == DataKind::Bytes && filled[3].start == 0x26);
+    }
+}
@@ -0,0 +1,287 @@
+// FLAGS bits and how the arithmetic, logic, shift and unary instructions set them. The flags that
+// Intel leaves undefined are set the way an 8086 leaves them.
+
+pub const CF: u16 = 0x0001;
+pub const PF: u16 = 0x0004;
+pub const AF: u16 = 0x0010;
+pub const ZF: u16 = 0x0040;
+pub const SF: u16 = 0x0080;
+pub const TF: u16 = 0x0100;
+pub const IF: u16 = 0x0200;
+pub const DF: u16 = 0x0400;
+pub const OF: u16 = 0x0800;
+
+// Bit and letter of each flag, from the high bit down
+const LETTERS: [(u16, char); 9] = [
+    (OF, 'O'),
+    (DF, 'D'),
+    (IF, 'I'),
+    (TF, 'T'),
+    (SF, 'S'),
+    (ZF, 'Z'),
+    (AF, 'A'),
+    (PF, 'P'),
+    (CF, 'C'),
+];
+
+pub fn letters(flags: u16) -> String {
+    LETTERS
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, letter)| letter)
+        .collect()
+}
+
+// Operand widths as in REGISTER_MAP: 0 is a byte and 1 a word
+fn mask(width: usize) -> u16 {
+    if width == 0 { 0xFF } else { 0xFFFF }
+}
+
+fn sign(width: usize) -> u16 {
+    if width == 0 { 0x80 } else { 0x8000 }
+}
+
+fn set(flags: u16, bit: u16, value: bool) -> u16 {
+    if value { flags | bit } else { flags & !bit }
+}
+
+fn signed(width: usize, value: u16) -> i32 {
+    if width == 0 {
+        value as u8 as i8 as i32
+    } else {
+        value as i16 as i32
+    }
+}
+
+// SF, ZF and PF of a result, parity only looks at the low byte
+fn sign_zero_parity(flags: u16, width: usize, result: u16) -> u16 {
+    let flags = set(flags, SF, result & sign(width) != 0);
+    let flags = set(flags, ZF, result & mask(width) == 0);
+    set(flags, PF, (result as u8).count_ones().is_multiple_of(2))
+}
+
+// The instructions of ALU_NAMES and test. cmp and test only set the flags, the caller drops
+// their result.
+pub fn alu(mnemonic: &str, width: usize, dst: u16, src: u16, flags: u16) -> (u16, u16) {
+    let (dst, src) = (dst & mask(width), src & mask(width));
+    let carry = (flags & CF) as u32;
+    let (wide, subtract) = match mnemonic {
+        "add" => (dst as u32 + src as u32, false),
+        "adc" => (dst as u32 + src as u32 + carry, false),
+        "sub" | "cmp" => ((dst as u32).wrapping_sub(src as u32), true),
+        "sbb" => ((dst as u32).wrapping_sub(src as u32 + carry), true),
+        "and" | "test" => return logic(width, dst & src, flags),
+        "or" => return logic(width, dst | src, flags),
+        "xor" => return logic(width, dst ^ src, flags),
+        _ => unreachable!("{mnemonic} is not an ALU instruction"),
+    };
+    let result = wide as u16 & mask(width);
+
+    let flags = sign_zero_parity(flags, width, result);
+    let flags = set(flags, CF, wide & (mask(width) as u32 + 1) != 0);
+    let flags = set(flags, AF, (dst ^ src ^ result) & 0x10 != 0);
+    // Overflow when both inputs of an addition have the same sign and the result the other
+    let overflow = if subtract {
+        (dst ^ src) & (dst ^ result)
+    } else {
+        !(dst ^ src) & (dst ^ result)
+    };
+    (result, set(flags, OF, overflow & sign(width) != 0))
+}
+
+// CF and OF are cleared, the 8086 clears AF too
+fn logic(width: usize, result: u16, flags: u16) -> (u16, u16) {
+    let flags = sign_zero_parity(flags & !(CF | OF | AF), width, result);
+    (result & mask(width), flags)
+}
+
+// inc and dec leave CF alone
+pub fn inc_dec(mnemonic: &str, width: usize, value: u16, flags: u16) -> (u16, u16) {
+    let operation = if mnemonic == "inc" { "add" } else { "sub" };
+    let (result, new_flags) = alu(operation, width, value, 1, flags);
+    (result, new_flags & !CF | flags & CF)
+}
+
+// mul and imul of the accumulator, the product is twice as wide. CF and OF tell whether the high
+// half is needed: for mul whether it is not 0, for imul whether it is more than the sign of the low
+// half. SF, ZF and PF follow the high half and AF is cleared.
+pub fn multiply(mnemonic: &str, width: usize, dst: u16, src: u16, flags: u16) -> (u32, u16) {
+    let bits = if width == 0 { 8 } else { 16 };
+    let product = if mnemonic == "imul" {
+        (signed(width, dst) * signed(width, src)) as u32
+    } else {
+        (dst & mask(width)) as u32 * (src & mask(width)) as u32
+    };
+    let low = product as u16 & mask(width);
+    let high = (product >> bits) as u16 & mask(width);
+    let sign_of_low = if mnemonic == "imul" && low & sign(width) != 0 {
+        mask(width)
+    } else {
+        0
+    };
+    let overflow = high != sign_of_low;
+    let flags = sign_zero_parity(flags & !AF, width, high);
+    let flags = set(set(flags, CF, overflow), OF, overflow);
+    (product & if width == 0 { 0xFFFF } else { 0xFFFF_FFFF }, flags)
+}
+
+// daa, das, aaa and aas on AX, after an addition or a subtraction of BCD digits. A low digit over 9
+// or AF adjusts the low digit and sets AF. daa and das then adjust the high digit when AL was over
+// 0x99 or CF was set, and set SF, ZF and PF. aaa and aas carry into AH instead, the 8086 adds the 6
+// to AL alone, and keep the low digit of AL. OF is left alone.
+pub fn decimal_adjust(mnemonic: &str, ax: u16, flags: u16) -> (u16, u16) {
+    let [mut al, mut ah] = ax.to_le_bytes();
+    let low_adjust = al & 0x0F > 9 || flags & AF != 0;
+    let add = matches!(mnemonic, "daa" | "aaa");
+    let step = |value: u8, amount: u8| {
+        if add {
+            value.wrapping_add(amount)
+        } else {
+            value.wrapping_sub(amount)
+        }
+    };
+    let flags = set(flags, AF, low_adjust);
+
+    if mnemonic.starts_with('d') {
+        let carry = al > 0x99 || flags & CF != 0;
+        // das also borrows when the low adjust goes below 0
+        let borrow = !add && low_adjust && al < 6;
+        if low_adjust {
+            al = step(al, 6);
+        }
+        if carry {
+            al = step(al, 0x60);
+        }
+        let flags = set(flags, CF, carry || borrow);
+        let flags = sign_zero_parity(flags, 0, al as u16);
+        return (u16::from_le_bytes([al, ah]), flags);
+    }
+
+    if low_adjust {
+        al = step(al, 6);
+        ah = step(ah, 1);
+    }
+    (
+        u16::from_le_bytes([al & 0x0F, ah]),
+        set(flags, CF, low_adjust),
+    )
+}
+
+// aam splits AL into the digits AL / base in AH and AL % base in AL, None when base is 0 and the
+// 8086 raises a divide error. SF, ZF and PF follow AL, OF, AF and CF are undefined and kept.
+pub fn aam(ax: u16, base: u8, flags: u16) -> Option<(u16, u16)> {
+    let al = ax as u8;
+    let (high, low) = (al.checked_div(base)?, al % base);
+    let flags = sign_zero_parity(flags, 0, low as u16);
+    Some((u16::from_le_bytes([low, high]), flags))
+}
+
+// aad turns AH and AL into the binary AH * base + AL in AL, the 8086 does it with an add that sets
+// all of its flags
+pub fn aad(ax: u16, base: u8, flags: u16) -> (u16, u16) {
+    let [al, ah] = ax.to_le_bytes();
+    alu("add", 0, al as u16, ah.wrapping_mul(base) as u16, flags)
+}
+
+// The 8086 does not mask the count, it shifts one bit at a time as often as CL says. Every step
+// sets CF to the bit shifted out and OF to whether the sign changed, so after a multi-bit shift OF
+// tells about the last step. Shifts also set SF, ZF and PF, and AF to bit 4 of the result.
+// A count of 0 changes nothing.
+pub fn shift(mnemonic: &str, width: usize, value: u16, count: u8, flags: u16) -> (u16, u16) {
+    let (mask, sign) = (mask(width), sign(width));
+    let mut result = value & mask;
+    let mut flags = flags;
+    for _ in 0..count {
+        let before = result;
+        let carry = flags & CF != 0;
+        let (shifted, carry_out) = match mnemonic {
+            "rol" => (result << 1 | (result & sign != 0) as u16, result & sign != 0),
+            "ror" => (result >> 1 | if result & 1 != 0 { sign } else { 0 }, result & 1 != 0),
+            "rcl" => (result << 1 | carry as u16, result & sign != 0),
+            "rcr" => (result >> 1 | if carry { sign } else { 0 }, result & 1 != 0),
+            "shl" => (result << 1, result & sign != 0),
+            "shr" => (result >> 1, result & 1 != 0),
+            "sar" => (result >> 1 | result & sign, result & 1 != 0),
+            _ => unreachable!("{mnemonic} is not a shift"),
+        };
+        result = shifted & mask;
+        flags = set(flags, CF, carry_out);
+        flags = set(flags, OF, (before ^ result) & sign != 0);
+    }
+
+    if count > 0 && !mnemonic.starts_with('r') {
+        flags = sign_zero_parity(flags, width, result);
+        flags = set(flags, AF, result & 0x10 != 0);
+    }
+    (result, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_and_sub_set_every_arithmetic_flag() {
+        assert_eq!(alu("add", 0, 0x7F, 1, 0), (0x80, OF | SF | AF));
+        assert_eq!(alu("sub", 0, 0, 1, 0), (0xFF, SF | AF | PF | CF));
+        assert_eq!(alu("adc", 1, 0xFFFF, 0, CF), (0, ZF | AF | PF | CF));
+        assert_eq!(alu("xor", 0, 0x0F, 0x0F, OF | AF | CF), (0, ZF | PF));
+    }
+
+    #[test]
+    fn inc_and_dec_keep_cf() {
+        assert_eq!(inc_dec("inc", 0, 0xFF, CF), (0, ZF | AF | PF | CF));
+        assert_eq!(inc_dec("dec", 1, 0x8000, 0), (0x7FFF, OF | AF | PF));
+    }
+
+    #[test]
+    fn multiply_sets_the_flags_from_the_high_half() {
+        assert_eq!(multiply("mul", 0, 0x80, 0x02, 0), (0x0100, OF | CF));
+        assert_eq!(multiply("mul", 0, 0x10, 0x02, AF), (0x0020, ZF | PF));
+        assert_eq!(
+            multiply("mul", 1, 0xFFFF, 0xFFFF, 0),
+            (0xFFFE_0001, OF | SF | CF)
+        );
+        // -1 * 1 fits in AL, its high half is only the sign
+        assert_eq!(multiply("imul", 0, 0xFF, 0x01, 0), (0xFFFF, SF | PF));
+        assert_eq!(multiply("imul", 0, 0x40, 0x02, 0), (0x0080, OF | ZF | PF | CF));
+        assert_eq!(
+            multiply("imul", 1, 0x8000, 0xFFFF, 0),
+            (0x0000_8000, OF | ZF | PF | CF)
+        );
+    }
+
+    #[test]
+    fn decimal_adjust_after_addition_and_subtraction() {
+        // 79 + 35 = 0xAE, adjusted to 14 with a carry
+        assert_eq!(decimal_adjust("daa", 0x00AE, 0), (0x0014, AF | PF | CF));
+        // 35 - 47 = 0xEE with a borrow, adjusted to 88
+        assert_eq!(decimal_adjust("das", 0x00EE, AF | CF), (0x0088, SF | AF | PF | CF));
+        // das borrows from the low adjust alone
+        assert_eq!(decimal_adjust("das", 0x0003, AF), (0x00FD, SF | AF | CF));
+        assert_eq!(decimal_adjust("aaa", 0x000F, 0), (0x0105, AF | CF));
+        assert_eq!(decimal_adjust("aas", 0x0208, AF), (0x0102, AF | CF));
+        assert_eq!(decimal_adjust("aaa", 0x0005, OF), (0x0005, OF));
+    }
+
+    #[test]
+    fn aam_and_aad() {
+        assert_eq!(aam(0x0025, 10, 0), Some((0x0307, 0)));
+        assert_eq!(aam(0x0025, 16, 0), Some((0x0205, PF)));
+        assert_eq!(aam(0x0025, 0, 0), None);
+        assert_eq!(aam(0x0025, 10, OF | AF | CF), Some((0x0307, OF | AF | CF)));
+        assert_eq!(aad(0x0307, 10, 0), (0x0025, AF));
+    }
+
+    #[test]
+    fn shifts_go_one_bit_at_a_time() {
+        assert_eq!(shift("shr", 0, 0x81, 1, 0), (0x40, OF | CF));
+        // The count is not masked, the 1 leaves the byte at the eighth step
+        assert_eq!(shift("shl", 0, 0x01, 9, 0), (0, ZF | PF));
+        assert_eq!(shift("rcl", 0, 0x80, 1, CF), (0x01, OF | CF));
+        assert_eq!(shift("sar", 1, 0x8000, 15, 0), (0xFFFF, SF | AF | PF));
+        // Rotates only change CF and OF
+        assert_eq!(shift("ror", 0, 0x01, 1, ZF), (0x80, ZF | OF | CF));
+        assert_eq!(shift("shl", 0, 0x81, 0, AF), (0x81, AF));
+    }
+}
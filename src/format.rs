@@ -0,0 +1,596 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::cpu::Change;
+use crate::flags;
+use crate::project::{OperandDisplay, Project};
+use crate::{
+    CONDITIONAL_JMP_NAMES, EFFECTIVE_ADDRESS_BASES, EffectiveAddress, Instruction, LOCK_PREFIX,
+    Operand, REGISTER_MAP, REPNE_PREFIX, SEGMENT_REGS,
+};
+use crate::{i386, nec};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Intel,
+    Nec,
+}
+
+// How a linker patches the bytes at a fixup location
+#[derive(Clone, Copy, PartialEq)]
+pub enum FixupKind {
+    Offset,
+    LowByte,
+    HighByte,
+    Segment,
+    // Segment and offset of a far pointer
+    Pointer,
+    // Offset relative to the end of the instruction
+    Relative,
+}
+
+// A reference the linker resolves, shown by name instead of the placeholder bytes
+#[derive(Clone)]
+pub struct Fixup {
+    pub kind: FixupKind,
+    pub symbol: String,
+}
+
+pub struct Formatter {
+    syntax: Syntax,
+    // Address of the first byte, without one jump targets are written relative to $
+    origin: Option<u32>,
+    // Names of addresses, used for jump and call targets
+    labels: BTreeMap<u32, String>,
+    // Names of the addresses of direct memory operands
+    data_labels: BTreeMap<u32, String>,
+    // Fixups by offset of the patched bytes
+    fixups: BTreeMap<usize, Fixup>,
+    // Comments and operand display overrides of a project file, by address
+    comments: BTreeMap<u32, String>,
+    operand_displays: BTreeMap<u32, OperandDisplay>,
+}
+
+impl Formatter {
+    pub fn new(syntax: Syntax, origin: Option<u32>) -> Self {
+        Formatter {
+            syntax,
+            origin,
+            labels: BTreeMap::new(),
+            data_labels: BTreeMap::new(),
+            fixups: BTreeMap::new(),
+            comments: BTreeMap::new(),
+            operand_displays: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_labels(mut self, labels: BTreeMap<u32, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_data_labels(mut self, data_labels: BTreeMap<u32, String>) -> Self {
+        self.data_labels = data_labels;
+        self
+    }
+
+    pub fn with_fixups(mut self, fixups: BTreeMap<usize, Fixup>) -> Self {
+        self.fixups = fixups;
+        self
+    }
+
+    // Adds the annotations of a project file for this segment, labels name direct memory
+    // operands too when names_data is set. Labels need absolute addresses, so without an origin
+    // the listing starts at 0.
+    pub fn with_project(
+        mut self,
+        project: &Project,
+        segment: Option<u16>,
+        names_data: bool,
+    ) -> Self {
+        self.origin.get_or_insert(0);
+        for (address, annotation) in project.annotations(segment) {
+            if let Some(label) = &annotation.label {
+                self.labels.insert(address, label.clone());
+                if names_data {
+                    self.data_labels.insert(address, label.clone());
+                }
+            }
+            if let Some(comment) = &annotation.comment {
+                self.comments.insert(address, comment.clone());
+            }
+            if let Some(display) = annotation.operand {
+                self.operand_displays.insert(address, display);
+            }
+        }
+        self
+    }
+
+    // The label of the instruction at offset, if any
+    pub fn label(&self, offset: usize) -> Option<&str> {
+        let address = self.origin? + offset as u32;
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn comment(&self, offset: usize) -> Option<&str> {
+        let address = self.origin? + offset as u32;
+        self.comments.get(&address).map(String::as_str)
+    }
+
+    // Offset of the next label or comment after offset, where data lines are split
+    pub fn next_annotation(&self, offset: usize) -> Option<usize> {
+        let origin = self.origin?;
+        let after = origin + offset as u32 + 1;
+        let label = self
+            .labels
+            .range(after..)
+            .next()
+            .map(|(&address, _)| address);
+        let comment = self
+            .comments
+            .range(after..)
+            .next()
+            .map(|(&address, _)| address);
+        let address = label.into_iter().chain(comment).min()?;
+        Some((address - origin) as usize)
+    }
+
+    fn operand_display(&self, offset: usize) -> Option<OperandDisplay> {
+        let address = self.origin? + offset as u32;
+        self.operand_displays.get(&address).copied()
+    }
+
+    // offset is where the instruction starts, counted from the origin
+    pub fn format(&self, instruction: &Instruction, offset: usize, buffer: &mut String) {
+        let prefixes = &instruction.prefixes;
+        for &prefix in &prefixes.bytes[..prefixes.stray] {
+            write_stray_prefix(buffer, prefix, "prefix repeated or out of order");
+        }
+        // The prefixes of an invalid encoding are written as part of its bytes, all in hex as
+        // they are code and not text
+        if matches!(instruction.mnemonic, "db" | "esc") {
+            let bytes = prefixes.bytes[prefixes.stray..]
+                .iter()
+                .map(|&prefix| prefix as i32)
+                .chain(instruction.operands.iter().map(|operand| match *operand {
+                    Operand::Imm(byte) => byte,
+                    _ => unreachable!("invalid encodings only hold bytes"),
+                }));
+            buffer.push_str("db ");
+            for (idx, byte) in bytes.enumerate() {
+                if idx > 0 {
+                    buffer.push_str(", ");
+                }
+                write!(buffer, "{byte:#04x}").unwrap();
+            }
+            // An instruction behind misplaced size prefixes is only kept as bytes
+            let reason = if instruction.mnemonic == "esc" {
+                "coprocessor instruction"
+            } else if prefixes.stray > 0 && (prefixes.operand_size || prefixes.address_size) {
+                "instruction behind a misplaced size prefix"
+            } else {
+                "invalid encoding"
+            };
+            write!(buffer, " ; {reason}").unwrap();
+            return;
+        }
+        let spelled_out = prefixes.spelled_out();
+        // Misplaced prefixes are written as raw bytes, an assembler would reject or drop them
+        if prefixes.lock && spelled_out {
+            if instruction.is_lockable() {
+                write!(buffer, "{} ", self.mnemonic("lock")).unwrap();
+            } else {
+                write_stray_prefix(
+                    buffer,
+                    LOCK_PREFIX,
+                    "lock on an instruction that cannot be locked",
+                );
+            }
+        }
+        if let Some(rep) = prefixes.rep
+            && spelled_out
+        {
+            if !instruction.is_string_op() {
+                write_stray_prefix(buffer, rep, "rep on a non-string instruction");
+            } else if rep == REPNE_PREFIX {
+                write!(buffer, "{} ", self.mnemonic("repne")).unwrap();
+            } else if instruction.compares_strings() {
+                write!(buffer, "{} ", self.mnemonic("repe")).unwrap();
+            } else {
+                write!(buffer, "{} ", self.mnemonic("rep")).unwrap();
+            }
+        }
+
+        // The size prefixes are only spelled out when no operand or mnemonic implies them
+        if prefixes.operand_size && spelled_out && !implies_operand_size(instruction) {
+            buffer.push_str("o32 ");
+        }
+        if prefixes.address_size && spelled_out && !implies_address_size(instruction) {
+            buffer.push_str("a32 ");
+        }
+
+        let has_memory_operand = instruction
+            .operands
+            .iter()
+            .any(|operand| matches!(operand, Operand::Mem { .. }));
+        // Without a memory operand to attach to, the override is written as a plain prefix
+        if !has_memory_operand
+            && spelled_out
+            && let Some(seg_idx) = prefixes.segment
+        {
+            write!(buffer, "{} ", self.segment_register(seg_idx)).unwrap();
+        }
+
+        let fixups = self.operand_fixups(instruction, offset);
+        buffer.push_str(self.mnemonic(instruction.mnemonic));
+        for (idx, operand) in instruction.operands.iter().enumerate() {
+            buffer.push_str(if idx == 0 { " " } else { ", " });
+            if idx == 0 && instruction.far {
+                buffer.push_str("far ");
+            }
+            let fixup = fixups
+                .iter()
+                .find(|(operand_idx, _)| *operand_idx == idx)
+                .map(|(_, fixup)| *fixup);
+            self.write_operand(buffer, operand, instruction, offset, fixup);
+        }
+    }
+
+    // Matches the fixups inside the instruction bytes to the operands they patch. The operand
+    // fields come last in an instruction, with an immediate behind any displacement.
+    fn operand_fixups(&self, instruction: &Instruction, offset: usize) -> Vec<(usize, &Fixup)> {
+        let position = |wanted: fn(&Operand) -> bool| instruction.operands.iter().position(wanted);
+
+        self.fixups
+            .range(offset..offset + instruction.size)
+            .filter_map(|(&location, fixup)| {
+                let ends_instruction =
+                    location + fixup_size(fixup.kind) == offset + instruction.size;
+                let operand_idx = match fixup.kind {
+                    FixupKind::Relative => position(|operand| matches!(operand, Operand::Rel(..))),
+                    FixupKind::Pointer => position(|operand| matches!(operand, Operand::Far(..))),
+                    _ => ends_instruction
+                        .then(|| position(|operand| matches!(operand, Operand::Imm(_))))
+                        .flatten()
+                        .or_else(|| position(|operand| matches!(operand, Operand::Mem { .. }))),
+                };
+                Some((operand_idx?, fixup))
+            })
+            .collect()
+    }
+
+    fn write_operand(
+        &self,
+        buffer: &mut String,
+        operand: &Operand,
+        instruction: &Instruction,
+        offset: usize,
+        fixup: Option<&Fixup>,
+    ) {
+        let display = self.operand_display(offset);
+        match *operand {
+            Operand::Reg(reg_idx, width) => buffer.push_str(self.register(reg_idx, width)),
+            Operand::SegReg(seg_idx) => buffer.push_str(self.segment_register(seg_idx)),
+            Operand::Mem {
+                ref address,
+                width,
+                sized,
+            } => {
+                if sized {
+                    write_effective_address_size(buffer, width);
+                }
+                if let Some(seg_idx) = instruction.prefixes.segment
+                    && instruction.prefixes.spelled_out()
+                {
+                    write!(buffer, "{}:", self.segment_register(seg_idx)).unwrap();
+                }
+                match fixup {
+                    Some(fixup) => self.write_symbolic_address(buffer, address, fixup),
+                    None => self.write_effective_address(buffer, address, display),
+                }
+            }
+            Operand::Imm(immediate) => {
+                if instruction.wide_immediate {
+                    buffer.push_str("strict ");
+                    write_effective_address_size(buffer, instruction.prefixes.width(1));
+                }
+                match fixup {
+                    Some(fixup) => write_symbol(buffer, fixup, immediate),
+                    None => self.write_number(buffer, immediate, display),
+                }
+            }
+            Operand::Rel(disp, width) => {
+                // Keep the assembler from choosing a shorter encoding than the original
+                if width == 2 {
+                    buffer.push_str("dword ");
+                } else if width == 1 && CONDITIONAL_JMP_NAMES.contains(&instruction.mnemonic) {
+                    buffer.push_str("near ");
+                } else if width == 0 && instruction.mnemonic == "jmp" {
+                    buffer.push_str("short ");
+                }
+                if let Some(fixup) = fixup {
+                    buffer.push_str(&fixup.symbol);
+                    return;
+                }
+                match self.origin {
+                    Some(origin) => {
+                        let next = origin as i64 + (offset + instruction.size) as i64;
+                        // IP wraps around within the code segment
+                        let target = (next + disp as i64) as u16;
+                        match self.labels.get(&(target as u32)) {
+                            Some(label) => buffer.push_str(label),
+                            None => write!(buffer, "{target:#x}").unwrap(),
+                        }
+                    }
+                    None => write!(buffer, "$+{}+{disp}", instruction.size).unwrap(),
+                }
+            }
+            Operand::Far(segment, offset) => match fixup {
+                Some(fixup) => write!(buffer, "far {}", fixup.symbol).unwrap(),
+                None => write!(buffer, "{segment}:{offset}").unwrap(),
+            },
+        }
+    }
+
+    // The fixed-up displacement is replaced by the symbol, the bytes hold the addend
+    fn write_symbolic_address(
+        &self,
+        buffer: &mut String,
+        eff_add: &EffectiveAddress,
+        fixup: &Fixup,
+    ) {
+        buffer.push('[');
+        let disp = match *eff_add {
+            EffectiveAddress::Direct(address) => address as i32,
+            EffectiveAddress::Indirect(base_idx, disp, _) => {
+                let base = match self.syntax {
+                    Syntax::Intel => EFFECTIVE_ADDRESS_BASES[base_idx],
+                    Syntax::Nec => nec::NEC_EFFECTIVE_ADDRESS_BASES[base_idx],
+                };
+                write!(buffer, "{base} + ").unwrap();
+                disp.unwrap_or(0) as i32
+            }
+            _ => unreachable!("OMF fixups only come with 16-bit addressing"),
+        };
+        write_symbol(buffer, fixup, disp);
+        buffer.push(']');
+    }
+
+    fn write_effective_address(
+        &self,
+        buffer: &mut String,
+        eff_add: &EffectiveAddress,
+        display: Option<OperandDisplay>,
+    ) {
+        match *eff_add {
+            EffectiveAddress::Reg(_) => unreachable!("mod=0b11 is decoded as a register operand"),
+            EffectiveAddress::Direct(address) => match self.data_labels.get(&(address as u32)) {
+                Some(label) if display.is_none() => write!(buffer, "[{label}]").unwrap(),
+                _ => {
+                    buffer.push('[');
+                    self.write_number(buffer, address as i32, display);
+                    buffer.push(']');
+                }
+            },
+            EffectiveAddress::Indirect(base_idx, disp, wide) => {
+                let base = match self.syntax {
+                    Syntax::Intel => EFFECTIVE_ADDRESS_BASES[base_idx],
+                    Syntax::Nec => nec::NEC_EFFECTIVE_ADDRESS_BASES[base_idx],
+                };
+                // NASM leaves out a zero displacement and shortens one that fits in a byte, so
+                // these get the size they are encoded with. [bp] is a byte 0 after bp anyway.
+                let base = match disp {
+                    Some(disp) if wide && i8::try_from(disp).is_ok() => format!("word {base}"),
+                    Some(0) if base_idx != 6 => format!("byte {base}"),
+                    _ => base.to_string(),
+                };
+                match disp.filter(|&disp| disp != 0 || base_idx != 6 || wide) {
+                    None => write!(buffer, "[{}]", base).unwrap(),
+                    Some(disp) if disp < 0 && display != Some(OperandDisplay::Symbol) => {
+                        write!(buffer, "[{} - ", base).unwrap();
+                        self.write_number(buffer, disp.unsigned_abs() as i32, display);
+                        buffer.push(']');
+                    }
+                    Some(disp) => {
+                        write!(buffer, "[{} + ", base).unwrap();
+                        self.write_number(buffer, disp as i32, display);
+                        buffer.push(']');
+                    }
+                }
+            }
+            EffectiveAddress::Indirect32 {
+                base,
+                index,
+                scale,
+                disp,
+            } => {
+                buffer.push('[');
+                if base.is_none() && index.is_none() {
+                    // A bare disp32 needs the keyword, otherwise it is assembled as a 16-bit address
+                    write!(buffer, "dword {}", disp as u32).unwrap();
+                } else {
+                    if let Some(base) = base {
+                        buffer.push_str(REGISTER_MAP[base][2]);
+                    }
+                    if let Some(index) = index {
+                        if base.is_some() {
+                            buffer.push_str(" + ");
+                        }
+                        buffer.push_str(REGISTER_MAP[index][2]);
+                        if scale > 1 {
+                            write!(buffer, "*{scale}").unwrap();
+                        }
+                    }
+                    if disp > 0 {
+                        write!(buffer, " + {disp}").unwrap();
+                    } else if disp < 0 {
+                        write!(buffer, " - {}", disp.unsigned_abs()).unwrap();
+                    }
+                }
+                buffer.push(']');
+            }
+        }
+    }
+
+    // An immediate or displacement as the project file asks for, decimal by default. A symbol
+    // is the label at that address as a 16-bit offset, characters have to be printable.
+    fn write_number(&self, buffer: &mut String, value: i32, display: Option<OperandDisplay>) {
+        match display {
+            Some(OperandDisplay::Hex) if value < 0 => {
+                write!(buffer, "-{:#x}", value.unsigned_abs()).unwrap()
+            }
+            Some(OperandDisplay::Hex) => write!(buffer, "{value:#x}").unwrap(),
+            Some(OperandDisplay::Char) if (0x20..0x7F).contains(&value) && value != '\'' as i32 => {
+                write!(buffer, "'{}'", value as u8 as char).unwrap()
+            }
+            Some(OperandDisplay::Symbol) => {
+                let address = value as u16 as u32;
+                match self
+                    .data_labels
+                    .get(&address)
+                    .or_else(|| self.labels.get(&address))
+                {
+                    Some(label) => buffer.push_str(label),
+                    None => write!(buffer, "{value}").unwrap(),
+                }
+            }
+            _ => write!(buffer, "{value}").unwrap(),
+        }
+    }
+
+    // A change in the trace of --exec, "cx:0x0->0xc" or "[ds:0x1000]:0x00->0x34"
+    pub fn write_change(&self, change: &Change, buffer: &mut String) {
+        match *change {
+            Change::Register(reg_idx, old, new) => {
+                write!(buffer, "{}:{old:#x}->{new:#x}", self.register(reg_idx, 1))
+            }
+            Change::Segment(seg_idx, old, new) => {
+                write!(buffer, "{}:{old:#x}->{new:#x}", self.segment_register(seg_idx))
+            }
+            Change::Memory {
+                seg_idx,
+                offset,
+                width,
+                old,
+                new,
+            } => {
+                // Bytes with two digits and words with four
+                let digits = if width == 0 { 4 } else { 6 };
+                write!(
+                    buffer,
+                    "[{}:{offset:#x}]:{old:#0digits$x}->{new:#0digits$x}",
+                    self.segment_register(seg_idx)
+                )
+            }
+            Change::Ip(old, new) => write!(buffer, "ip:{old:#x}->{new:#x}"),
+            Change::Flags(old, new) => write!(
+                buffer,
+                "flags:{}->{}",
+                flags::letters(old),
+                flags::letters(new)
+            ),
+        }
+        .unwrap();
+    }
+
+    fn mnemonic(&self, inst_name: &'static str) -> &'static str {
+        match self.syntax {
+            Syntax::Intel => inst_name,
+            Syntax::Nec => nec::nec_mnemonic(inst_name),
+        }
+    }
+
+    fn register(&self, reg_idx: usize, width: usize) -> &'static str {
+        match self.syntax {
+            // NEC never named the 386 registers
+            Syntax::Nec if width < 2 => nec::NEC_REGISTER_MAP[reg_idx][width],
+            _ => REGISTER_MAP[reg_idx][width],
+        }
+    }
+
+    fn segment_register(&self, seg_idx: usize) -> &'static str {
+        match self.syntax {
+            Syntax::Intel => SEGMENT_REGS[seg_idx],
+            Syntax::Nec => nec::NEC_SEGMENT_REGS[seg_idx],
+        }
+    }
+}
+
+// A symbol with the addend stored in the fixup location
+pub fn write_symbol(buffer: &mut String, fixup: &Fixup, addend: i32) {
+    if fixup.kind == FixupKind::Segment {
+        buffer.push_str("seg ");
+    }
+    buffer.push_str(&fixup.symbol);
+    if fixup.kind != FixupKind::Segment && addend > 0 {
+        write!(buffer, " + {addend}").unwrap();
+    } else if fixup.kind != FixupKind::Segment && addend < 0 {
+        write!(buffer, " - {}", addend.unsigned_abs()).unwrap();
+    }
+}
+
+// Number of bytes the linker patches
+fn fixup_size(kind: FixupKind) -> usize {
+    match kind {
+        FixupKind::LowByte | FixupKind::HighByte => 1,
+        FixupKind::Offset | FixupKind::Segment | FixupKind::Relative => 2,
+        FixupKind::Pointer => 4,
+    }
+}
+
+fn write_stray_prefix(buffer: &mut String, prefix: u8, reason: &str) {
+    writeln!(buffer, "db {prefix:#04x} ; {reason}").unwrap();
+}
+
+fn write_effective_address_size(buffer: &mut String, width: usize) {
+    match width {
+        0 => buffer.push_str("byte "),
+        1 => buffer.push_str("word "),
+        _ => buffer.push_str("dword "),
+    }
+}
+
+fn implies_operand_size(instruction: &Instruction) -> bool {
+    i386::DWORD_MNEMONICS.contains(&instruction.mnemonic)
+        || instruction.operands.iter().any(|operand| {
+            matches!(
+                operand,
+                Operand::Reg(_, 2) | Operand::Mem { width: 2, .. } | Operand::Rel(_, 2)
+            )
+        })
+}
+
+fn implies_address_size(instruction: &Instruction) -> bool {
+    instruction.operands.iter().any(|operand| {
+        matches!(
+            operand,
+            Operand::Mem {
+                address: EffectiveAddress::Indirect32 { .. },
+                ..
+            }
+        )
+    })
+}
+
+// Raw bytes as a db line, runs of printable characters are kept together as a quoted string
+pub fn write_db(buffer: &mut String, bytes: &[u8]) {
+    let printable = |byte: &u8| (b' '..=b'~').contains(byte) && *byte != b'"';
+
+    buffer.push_str("db ");
+    let mut rest = bytes;
+    while let Some(&byte) = rest.first() {
+        if rest.len() < bytes.len() {
+            buffer.push_str(", ");
+        }
+        let run = rest.iter().take_while(|byte| printable(byte)).count();
+        if run > 0 {
+            buffer.push('"');
+            buffer.extend(rest[..run].iter().map(|&byte| byte as char));
+            buffer.push('"');
+            rest = &rest[run..];
+        } else {
+            write!(buffer, "{byte:#04x}").unwrap();
+            rest = &rest[1..];
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Intel HEX and Motorola S-record files: text records that place data at absolute addresses.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+// A contiguous run of bytes and the address it is loaded at
+pub struct Region {
+    pub address: u32,
+    pub bytes: Vec<u8>,
+}
+
+// Every record starts a line with ':' (Intel HEX) or 'S' and a digit (S-record), and the first
+// one has to be a whole record with a matching length and checksum
+pub fn detect(bytes: &[u8]) -> InputFormat {
+    let mut lines = bytes
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty())
+        .peekable();
+    let Some(first) = lines.peek().copied() else {
+        return InputFormat::Binary;
+    };
+
+    let is_ihex = |line: &[u8]| line[0] == b':' && line[1..].iter().all(u8::is_ascii_hexdigit);
+    let is_srec = |line: &[u8]| {
+        line.len() >= 2 && line[0] == b'S' && line[1..].iter().all(u8::is_ascii_hexdigit)
+    };
+    if is_ihex(first) && is_intel_hex_record(first) && lines.all(is_ihex) {
+        InputFormat::IntelHex
+    } else if is_srec(first) && is_srecord(first) && lines.all(is_srec) {
+        InputFormat::SRecord
+    } else {
+        InputFormat::Binary
+    }
+}
+
+// The bytes of a line of hex digits, None for an odd number of digits
+fn hex_bytes(digits: &[u8]) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn is_intel_hex_record(line: &[u8]) -> bool {
+    hex_bytes(&line[1..]).is_some_and(|record| {
+        record.len() >= 5
+            && record.len() == record[0] as usize + 5
+            && record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+    })
+}
+
+fn is_srecord(line: &[u8]) -> bool {
+    hex_bytes(&line[2..]).is_some_and(|record| {
+        let sum = record
+            .iter()
+            .take(record.len().saturating_sub(1))
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        record.len() >= 2
+            && record.len() == record[0] as usize + 1
+            && !sum == record[record.len() - 1]
+    })
+}
+
+pub fn parse(bytes: &[u8], format: InputFormat) -> Vec<Region> {
+    let text = std::str::from_utf8(bytes).expect("Hex file is not ASCII text");
+    let chunks = match format {
+        InputFormat::IntelHex => parse_intel_hex(text),
+        InputFormat::SRecord => parse_srecord(text),
+        InputFormat::Binary => unreachable!(),
+    };
+
+    merge(chunks)
+}
+
+// The hex digits after the record mark, as bytes, with the checksum verified by the caller
+fn record_bytes(line_idx: usize, digits: &str) -> Vec<u8> {
+    if !digits.len().is_multiple_of(2) {
+        panic!("Odd number of hex digits on line {}", line_idx + 1);
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|idx| {
+            u8::from_str_radix(&digits[idx..idx + 2], 16)
+                .unwrap_or_else(|_| panic!("Invalid hex digits on line {}", line_idx + 1))
+        })
+        .collect()
+}
+
+fn parse_intel_hex(text: &str) -> Vec<(u32, Vec<u8>)> {
+    const DATA: u8 = 0x00;
+    const END_OF_FILE: u8 = 0x01;
+    const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+    const START_SEGMENT_ADDRESS: u8 = 0x03;
+    const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+    const START_LINEAR_ADDRESS: u8 = 0x05;
+
+    let mut chunks = Vec::new();
+    // Added to the 16-bit record addresses, set by the extended address records
+    let mut base = 0u32;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(digits) = line.strip_prefix(':') else {
+            panic!("Missing ':' record mark on line {}", line_idx + 1);
+        };
+        let record = record_bytes(line_idx, digits);
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            panic!("Malformed Intel HEX record on line {}", line_idx + 1);
+        }
+        // All bytes including the checksum add up to zero
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            panic!("Intel HEX checksum mismatch on line {}", line_idx + 1);
+        }
+
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            DATA => chunks.push((base + address, data.to_vec())),
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16
+            }
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            record_type => panic!(
+                "Unsupported Intel HEX record type {record_type:#04x} on line {}",
+                line_idx + 1
+            ),
+        }
+    }
+
+    chunks
+}
+
+fn parse_srecord(text: &str) -> Vec<(u32, Vec<u8>)> {
+    let mut chunks = Vec::new();
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() < 2 || !line.starts_with('S') {
+            panic!("Missing 'S' record mark on line {}", line_idx + 1);
+        }
+        let record_type = line.as_bytes()[1];
+        let record = record_bytes(line_idx, &line[2..]);
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            panic!("Malformed S-record on line {}", line_idx + 1);
+        }
+        // The checksum is the ones' complement of the sum of everything before it
+        let sum = record[..record.len() - 1]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if !sum != record[record.len() - 1] {
+            panic!("S-record checksum mismatch on line {}", line_idx + 1);
+        }
+
+        // Header, count and start address records carry no data to load
+        let address_size = match record_type {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            b'0' | b'5' | b'6' | b'7' | b'8' | b'9' => continue,
+            _ => panic!(
+                "Unsupported S-record type S{} on line {}",
+                record_type as char,
+                line_idx + 1
+            ),
+        };
+        if record.len() < address_size + 2 {
+            panic!("Malformed S-record on line {}", line_idx + 1);
+        }
+        let address = record[1..=address_size]
+            .iter()
+            .fold(0u32, |address, &byte| address << 8 | byte as u32);
+        chunks.push((address, record[address_size + 1..record.len() - 1].to_vec()));
+    }
+
+    chunks
+}
+
+// Records may come in any order, adjacent ones are joined into a single region
+fn merge(mut chunks: Vec<(u32, Vec<u8>)>) -> Vec<Region> {
+    chunks.sort_by_key(|(address, _)| *address);
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (address, data) in chunks {
+        if data.is_empty() {
+            continue;
+        }
+        if let Some(last) = regions.last_mut() {
+            let end = last.address + last.bytes.len() as u32;
+            if address < end {
+                panic!("Overlapping data at address {address:#x}");
+            }
+            if address == end {
+                last.bytes.extend(data);
+                continue;
+            }
+        }
+        regions.push(Region {
+            address,
+            bytes: data,
+        });
+    }
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuMode;
+    use crate::format::Syntax;
+    use crate::tests::listing;
+
+    #[test]
+    fn intel_hex_records_are_joined_at_their_address() {
+        // Segment 1000h, mov ax, 0x1234 / int 0x20 at 0100h and a nop behind it
+        let file = b":020000021000EC\n:05010000B83412CD200F\n:010105009069\n:00000001FF\n";
+        assert!(detect(file) == InputFormat::IntelHex);
+        let regions = parse(file, InputFormat::IntelHex);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].address, 0x10100);
+        assert_eq!(
+            listing(&regions[0].bytes, CpuMode::I8086, Syntax::Intel),
+            ["mov ax, 4660", "int 32", "xchg ax, ax"]
+        );
+    }
+
+    #[test]
+    fn srecords_load_at_their_address() {
+        let file = b"S1080100B83412CD200B\r\nS9030100FB\r\n";
+        assert!(detect(file) == InputFormat::SRecord);
+        let regions = parse(file, InputFormat::SRecord);
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].address, 0x100);
+        assert_eq!(regions[0].bytes, [0xB8, 0x34, 0x12, 0xCD, 0x20]);
+    }
+
+    #[test]
+    fn text_without_a_valid_first_record_is_binary() {
+        assert!(detect(b":10 is not a record\n") == InputFormat::Binary);
+        assert!(detect(b"Setup\n") == InputFormat::Binary);
+        // One checksum digit off
+        assert!(detect(b":020000021000ED\n") == InputFormat::Binary);
+    }
+}
@@ -0,0 +1,162 @@
+// 386 real mode: 32-bit addressing and the common 0x0F opcodes.
+
+use crate::{
+    CONDITIONAL_JMP_NAMES, EffectiveAddress, Instruction, Operand, Prefixes, decode_effective_address_calculation, read_immediate, regmem_operand,
+};
+
+pub const FS_IDX: usize = 4;
+
+const SETCC_NAMES: [&str; 16] = [
+    "seto", "setno", "setb", "setnb", "sete", "setne", "setbe", "seta", "sets", "setns", "setp",
+    "setnp", "setl", "setge", "setle", "setg",
+];
+const BT_NAMES: [&str; 4] = ["bt", "bts", "btr", "btc"];
+
+// Mnemonics that already spell out the operand-size prefix, so no o32 is needed
+pub const DWORD_MNEMONICS: [&str; 14] = [
+    "movsd", "cmpsd", "scasd", "lodsd", "stosd", "insd", "outsd", "pushfd", "popfd", "pushad",
+    "popad", "iretd", "cwde", "cdq",
+];
+
+// The r/m field was already consumed, this reads the optional SIB byte and the displacement
+pub fn decode_address32(bytes: &mut &[u8], mod_bytes: u8, r_m: usize) -> EffectiveAddress {
+    const SIB_RM: usize = 0b100;
+    const NO_INDEX: usize = 0b100;
+    const DISP32_BASE: usize = 0b101;
+
+    let (mut base, index, scale) = if r_m == SIB_RM {
+        let sib = bytes[0];
+        *bytes = &bytes[1..];
+        let scale = 1 << (sib >> 6);
+        let index = ((sib >> 3) & 0b111) as usize;
+        let base = (sib & 0b111) as usize;
+        (Some(base), (index != NO_INDEX).then_some(index), scale)
+    } else {
+        (Some(r_m), None, 1)
+    };
+
+    // ebp as a base without a displacement encodes a bare disp32 instead
+    let disp = match mod_bytes {
+        0b00 if base == Some(DISP32_BASE) => {
+            base = None;
+            read_immediate(bytes, 2)
+        }
+        0b00 => 0,
+        0b01 => read_immediate(bytes, 0),
+        _ => read_immediate(bytes, 2),
+    };
+
+    EffectiveAddress::Indirect32 {
+        base,
+        index,
+        scale,
+        disp,
+    }
+}
+
+pub fn decode_extended(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
+    let encoding = *bytes;
+    let byte2 = bytes[1];
+    *bytes = &bytes[2..];
+    let width = prefixes.width(1);
+
+    match byte2 {
+        // jcc near
+        0x80..=0x8F => {
+            let disp = read_immediate(bytes, width);
+            let inst_name = CONDITIONAL_JMP_NAMES[(byte2 & 0b1111) as usize];
+
+            Instruction::new(inst_name, vec![Operand::Rel(disp, width)])
+        }
+        0x90..=0x9F => {
+            let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let inst_name = SETCC_NAMES[(byte2 & 0b1111) as usize];
+
+            Instruction::new(inst_name, vec![regmem_operand(eff_add, 0, true)])
+        }
+        0xA0 | 0xA8 => Instruction::new("push", vec![Operand::SegReg(fs_or_gs(byte2))]),
+        0xA1 | 0xA9 => Instruction::new("pop", vec![Operand::SegReg(fs_or_gs(byte2))]),
+        // bt/bts/btr/btc r/m, reg
+        0xA3 | 0xAB | 0xB3 | 0xBB => {
+            let inst_name = BT_NAMES[((byte2 >> 3) & 0b11) as usize];
+            let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, width, false);
+
+            Instruction::new(inst_name, vec![dst, Operand::Reg(reg, width)])
+        }
+        // bt/bts/btr/btc r/m, imm8
+        0xBA => {
+            let (reg_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, width, true);
+            let bit_idx = bytes[0];
+            *bytes = &bytes[1..];
+            // Only reg 4-7 are defined
+            if reg_idx < 4 {
+                return Instruction::invalid(&encoding[..encoding.len() - bytes.len()]);
+            }
+
+            Instruction::new(
+                BT_NAMES[reg_idx - 4],
+                vec![dst, Operand::Imm(bit_idx as i32)],
+            )
+        }
+        // shld/shrd r/m, reg, imm8 or cl
+        0xA4 | 0xA5 | 0xAC | 0xAD => {
+            let inst_name = if byte2 < 0xA8 { "shld" } else { "shrd" };
+            let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, width, false);
+            let count = if byte2 & 1 == 1 {
+                Operand::Reg(1, 0) // cl
+            } else {
+                let count = bytes[0];
+                *bytes = &bytes[1..];
+                Operand::Imm(count as i32)
+            };
+
+            Instruction::new(inst_name, vec![dst, Operand::Reg(reg, width), count])
+        }
+        // movzx/movsx reg, r/m8 or r/m16
+        0xB6 | 0xB7 | 0xBE | 0xBF => {
+            let inst_name = if byte2 < 0xB8 { "movzx" } else { "movsx" };
+            let src_width = (byte2 & 1) as usize;
+            let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let src = regmem_operand(eff_add, src_width, true);
+
+            Instruction::new(inst_name, vec![Operand::Reg(reg, width), src])
+        }
+        // imul, bsf and bsr reg, r/m
+        0xAF | 0xBC | 0xBD => {
+            let inst_name = match byte2 {
+                0xAF => "imul",
+                0xBC => "bsf",
+                _ => "bsr",
+            };
+            let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let src = regmem_operand(eff_add, width, false);
+
+            Instruction::new(inst_name, vec![Operand::Reg(reg, width), src])
+        }
+        // lss, lfs and lgs reg, m16:16
+        0xB2 | 0xB4 | 0xB5 => {
+            let inst_name = match byte2 {
+                0xB2 => "lss",
+                0xB4 => "lfs",
+                _ => "lgs",
+            };
+            let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let src = regmem_operand(eff_add, width, false);
+
+            Instruction::new(inst_name, vec![Operand::Reg(reg, width), src])
+        }
+        // The system instructions are left as bytes, with their ModR/M byte and displacement
+        0x00..=0x03 | 0x20..=0x23 => {
+            decode_effective_address_calculation(bytes, prefixes);
+            Instruction::invalid(&encoding[..encoding.len() - bytes.len()])
+        }
+        _ => Instruction::invalid(&encoding[..2]),
+    }
+}
+
+fn fs_or_gs(byte2: u8) -> usize {
+    FS_IDX + ((byte2 >> 3) & 1) as usize
+}
@@ -0,0 +1,102 @@
+// Interrupts the simulator can serve in Rust instead of running the code the vector table points
+// at, for the DOS and BIOS services a program expects to be there.
+
+use std::io::{self, Read};
+
+use crate::cpu::{AX, Cpu, DS, DX};
+use crate::flags::ZF;
+use crate::memory::Memory;
+
+// Gets every interrupt before the vector table does. An interrupt it handles returns to the
+// instruction after the one that raised it, with the registers and memory the handler left. The
+// ones it does not handle go through the vector table to the handlers in memory.
+pub trait InterruptHandler {
+    fn interrupt(&mut self, interrupt_type: u8, cpu: &mut Cpu, memory: &mut Memory) -> bool;
+}
+
+// Leaves every interrupt to the vector table
+pub struct VectorTable;
+
+impl InterruptHandler for VectorTable {
+    fn interrupt(&mut self, _: u8, _: &mut Cpu, _: &mut Memory) -> bool {
+        false
+    }
+}
+
+// The console services of DOS and the BIOS, for --dos. Keys are read from stdin, and what the
+// program prints is kept to be shown after the run, so it does not end up between the lines of
+// the listing.
+#[derive(Default)]
+pub struct DosServices {
+    pub output: Vec<u8>,
+    // A key int 16h function 1 looked at, still to be read
+    pending_key: Option<u8>,
+}
+
+impl DosServices {
+    // 0 once stdin has no more keys
+    fn read_key(&mut self) -> u8 {
+        self.pending_key.take().unwrap_or_else(|| {
+            let mut key = [0];
+            match io::stdin().read(&mut key) {
+                Ok(1) => key[0],
+                _ => 0,
+            }
+        })
+    }
+}
+
+impl InterruptHandler for DosServices {
+    fn interrupt(&mut self, interrupt_type: u8, cpu: &mut Cpu, memory: &mut Memory) -> bool {
+        let function = (cpu.registers[AX] >> 8) as u8;
+        match (interrupt_type, function) {
+            // Program terminate, and terminate with a return code in AL
+            (0x20, _) | (0x21, 0x4C) => cpu.halted = true,
+            // Read a character and echo it
+            (0x21, 0x01) => {
+                let key = self.read_key();
+                self.output.push(key);
+                cpu.set_register(AX, 0, key as u16);
+            }
+            // Write the character in DL
+            (0x21, 0x02) => {
+                self.output.push(cpu.registers[DX] as u8);
+                cpu.set_register(AX, 0, cpu.registers[DX] & 0xFF);
+            }
+            // Write the string at DS:DX up to a '$', a segment without one is written once
+            (0x21, 0x09) => {
+                let start = cpu.registers[DX];
+                let mut offset = start;
+                loop {
+                    let char = memory.read_u8(cpu.segments[DS], offset);
+                    if char == b'$' {
+                        break;
+                    }
+                    self.output.push(char);
+                    offset = offset.wrapping_add(1);
+                    if offset == start {
+                        break;
+                    }
+                }
+                cpu.set_register(AX, 0, b'$' as u16);
+            }
+            // Teletype output of AL
+            (0x10, 0x0E) => self.output.push(cpu.registers[AX] as u8),
+            // Wait for a key, AL is its character and AH the scan code, which stdin does not have
+            (0x16, 0x00) => cpu.registers[AX] = self.read_key() as u16,
+            // Whether a key is waiting: ZF clear and the key in AX if there is one
+            (0x16, 0x01) => {
+                let key = self.read_key();
+                if key == 0 {
+                    cpu.flags |= ZF;
+                } else {
+                    self.pending_key = Some(key);
+                    cpu.registers[AX] = key as u16;
+                    cpu.flags &= !ZF;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
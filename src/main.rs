@@ -1,21 +1,59 @@
+mod boot;
+mod com;
+mod clocks;
+mod cpu;
+mod data;
+mod flags;
+mod format;
+mod hexfile;
+mod i386;
+mod interrupts;
+mod memory;
+mod mz;
+mod ne;
+mod nec;
+mod omf;
+mod project;
+mod rom;
+mod symbols;
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fmt::Write;
 use std::fs;
+use std::io::{self, Read};
+use std::ops::Range;
+
+use cpu::{CS, Change, Cpu, SP};
+use data::{DataKind, DataRange};
+use format::{Formatter, Syntax};
+use hexfile::{InputFormat, Region};
+use interrupts::{DosServices, InterruptHandler, VectorTable};
+use memory::Memory;
+use mz::MzExecutable;
+use ne::NeExecutable;
+use omf::ObjectModule;
+use project::Project;
+use rom::Rom;
+use symbols::{Symbol, SymbolAddress};
 
 const W_BIT_MASK: u8 = 0b1;
 const S_BIT_SHIFT: u8 = 0b1;
 const S_BIT_MASK: u8 = 0b1;
 const GRP_INST_IDX_SHIFT: u8 = 3;
 const GRP_INST_IDX_MASK: u8 = 0b111;
-const REGISTER_MAP: [[&str; 2]; 8] = [
-    ["al", "ax"],
-    ["cl", "cx"],
-    ["dl", "dx"],
-    ["bl", "bx"],
-    ["ah", "sp"],
-    ["ch", "bp"],
-    ["dh", "si"],
-    ["bh", "di"],
+const REGISTER_MAP: [[&str; 3]; 8] = [
+    ["al", "ax", "eax"],
+    ["cl", "cx", "ecx"],
+    ["dl", "dx", "edx"],
+    ["bl", "bx", "ebx"],
+    ["ah", "sp", "esp"],
+    ["ch", "bp", "ebp"],
+    ["dh", "si", "esi"],
+    ["bh", "di", "edi"],
+];
+const EFFECTIVE_ADDRESS_BASES: [&str; 8] = [
+    "bx + si", "bx + di", "bp + si", "bp + di", "si", "di", "bp", "bx",
 ];
 const ALU_NAMES: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
 const CONDITIONAL_JMP_NAMES: [&str; 16] = [
@@ -23,483 +61,1511 @@ const CONDITIONAL_JMP_NAMES: [&str; 16] = [
     "jle", "jg",
 ];
 const LOOP_NAMES: [&str; 4] = ["loopnz", "loopz", "loop", "jcxz"];
-const SEGMENT_REGS: [&str; 4] = ["es", "cs", "ss", "ds"];
+const SEGMENT_REGS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
 const GRP1_NAMES: [&str; 8] = ["test", "???", "not", "neg", "mul", "imul", "div", "idiv"];
 const GRP2_NAMES: [&str; 8] = ["inc", "dec", "call", "call", "jmp", "jmp", "push", "???"];
 const SHIFT_NAMES: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "???", "sar"];
+// Read-modify-write instructions that accept lock, as long as they write to memory
+const LOCKABLE_NAMES: [&str; 15] = [
+    "add", "adc", "and", "btc", "btr", "bts", "dec", "inc", "neg", "not", "or", "sbb", "sub",
+    "xor", "xchg",
+];
+const LOCK_PREFIX: u8 = 0b11110000;
+const REPNE_PREFIX: u8 = 0b11110010;
+const REP_PREFIX: u8 = 0b11110011;
+// Keeps a program that never halts from running forever
+const DEFAULT_STEPS: usize = 100_000;
+// Enough for the longest 386 instruction after its prefixes
+const MAX_INSTRUCTION_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum CpuMode {
+    I8086,
+    // Decodes as the 8086, --exec counts the clocks of its 8-bit bus
+    I8088,
+    V20,
+    I386,
+}
 
-struct SegOverrideReg(Option<&'static str>);
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let file_path = if args.len() != 2 {
-        panic!("Usage: ./sim8086 path/to/binary/file");
-    } else {
-        args[1].parse::<String>().unwrap()
-    };
+impl CpuMode {
+    // The V20 and the 386 both have the instructions the 80186 added
+    fn has_186_instructions(self) -> bool {
+        matches!(self, CpuMode::V20 | CpuMode::I386)
+    }
+}
 
-    let instruction_stream = fs::read(file_path).expect("Could not read file");
+struct Options {
+    input: Input,
+    cpu_mode: CpuMode,
+    syntax: Syntax,
+    info: bool,
+    com: bool,
+    input_format: Option<InputFormat>,
+    rom: bool,
+    boot: bool,
+    start: Option<usize>,
+    end: Option<usize>,
+    length: Option<usize>,
+    origin: Option<Origin>,
+    // File offsets of tables and messages that are not decoded
+    data: Vec<DataRange>,
+    symbols: Vec<Symbol>,
+    // From --project, or found next to the input file
+    project: Option<Project>,
+    exec: bool,
+    // Number of instructions --exec runs at most
+    steps: usize,
+    // Show the clocks of every instruction --exec runs
+    clocks: bool,
+    // Show every register and memory change of --exec, not only the flags
+    trace: bool,
+    // Serve the DOS and BIOS console interrupts in the simulator
+    dos: bool,
+    // Resume interrupted repeated string instructions the way the 8086 does
+    rep_resume_bug: bool,
+}
 
-    let mut arg1 = String::with_capacity(128);
-    let mut arg2 = String::with_capacity(128);
-    println!("bits 16");
-    decode_instructions(&instruction_stream, &mut arg1, &mut arg2);
+enum Input {
+    File(String),
+    // Given as -
+    Stdin,
+    // Bytes from --hex
+    Bytes(Vec<u8>),
 }
 
-fn decode_instructions(mut bytes: &[u8], arg1: &mut String, arg2: &mut String) {
-    let mut segment_override = SegOverrideReg(None);
-    // The while loop is needed because different instructions have different lengths
-    'decode: while !bytes.is_empty() {
-        // Clear the arena like strings
-        arg1.clear();
-        arg2.clear();
+// Address of the first decoded byte, the segment is only used for the listing header
+#[derive(Clone, Copy)]
+struct Origin {
+    segment: Option<u16>,
+    offset: u32,
+}
 
-        let byte1 = bytes[0];
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = parse_args(&args);
+
+    let file = match &options.input {
+        Input::File(path) => fs::read(path).expect("Could not read file"),
+        Input::Stdin => {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .expect("Could not read stdin");
+            bytes
+        }
+        Input::Bytes(bytes) => bytes.clone(),
+    };
+    // Bytes given with --hex are machine code, even when they happen to spell out a record
+    let input_format = options.input_format.unwrap_or_else(|| match options.input {
+        Input::Bytes(_) => InputFormat::Binary,
+        _ => hexfile::detect(&file),
+    });
+
+    let is_omf = input_format == InputFormat::Binary && omf::is_omf(&file);
+    let plain_binary = input_format == InputFormat::Binary
+        && !options.rom
+        && !options.boot
+        && !is_omf
+        && !mz::is_mz(&file);
+    let has_range = options.start.is_some()
+        || options.end.is_some()
+        || options.length.is_some()
+        || options.origin.is_some();
+    if has_range && !plain_binary {
+        panic!("--start, --end, --length and --origin only apply to plain binaries and .COM files");
+    }
+    if !options.symbols.is_empty()
+        && (input_format != InputFormat::Binary || options.rom || options.boot || is_omf)
+    {
+        panic!("--symbols applies to plain binaries, .COM files and MZ executables");
+    }
+    if !options.data.is_empty() && (input_format != InputFormat::Binary || options.rom || is_omf) {
+        panic!("--data and --data-file need a binary file and cannot be used with --rom");
+    }
+    let is_8086 = matches!(options.cpu_mode, CpuMode::I8086 | CpuMode::I8088);
+    if options.exec && (!plain_binary || !is_8086) {
+        panic!("--exec and --trace run plain binaries and .COM files on an 8086 or 8088");
+    }
 
-        // Match 4 bit instructions
-        let opcode = byte1 >> 4;
-        match opcode {
-            0b1011 => {
-                decode_mov_imm_reg(&mut bytes);
-                continue 'decode;
-            }
-            _ => {}
-        };
+    if input_format != InputFormat::Binary {
+        if options.info || options.com || options.rom || options.boot {
+            panic!("--info, --com, --rom and --boot need a binary file");
+        }
 
-        // Match 5 bit instructions
-        let opcode = byte1 >> 3;
-        match opcode {
-            0b01010 => {
-                decode_one_byte_reg("push", &mut bytes);
-                continue 'decode;
-            }
-            0b01011 => {
-                decode_one_byte_reg("pop", &mut bytes);
-                continue 'decode;
-            }
-            0b01000 => {
-                decode_one_byte_reg("inc", &mut bytes);
-                continue 'decode;
-            }
-            0b01001 => {
-                decode_one_byte_reg("dec", &mut bytes);
-                continue 'decode;
-            }
-            0b10010 => {
-                decode_xchg_acc(&mut bytes);
-                continue 'decode;
+        println!("bits 16");
+        decode_regions(&hexfile::parse(&file, input_format), &options);
+    } else if options.rom {
+        let rom = Rom::parse(&file);
+        println!("bits 16");
+        rom.print_info();
+        decode_reachable(&rom, &options);
+    } else if options.boot {
+        decode_boot_sector(&file, &options);
+    } else if is_omf {
+        if options.info || options.com {
+            panic!("--info and --com cannot be used with an OMF object file");
+        }
+        decode_object_module(&ObjectModule::parse(&file), &options);
+    } else if mz::is_mz(&file) {
+        if options.com {
+            panic!("--com cannot be used with an MZ executable");
+        }
+        if let Some(header) = ne::ne_offset(&file) {
+            let exe = NeExecutable::parse(&file, header);
+            if options.info {
+                exe.print_info();
+            } else {
+                decode_new_executable(&exe, &options);
             }
-            _ => {}
-        };
+            return;
+        }
+        let exe = MzExecutable::parse(&file);
+        if options.info {
+            exe.print_info();
+            return;
+        }
 
-        // Match 6 bit instructions
-        let opcode = byte1 >> 2;
-        match opcode {
-            0b100000 => {
-                decode_alu_imm_regmem(&mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b100010 => {
-                decode_regmem_reg("mov", &mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b000000 | 0b000010 | 0b000100 | 0b000110 | 0b001000 | 0b001010 | 0b001100
-            | 0b001110 => {
-                let inst_idx = (byte1 >> 3 & 0b111) as usize;
-                let inst_name = ALU_NAMES[inst_idx];
-                decode_regmem_reg(inst_name, &mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b110100 => {
-                decode_shift_regmem(&mut bytes, arg1, &mut segment_override);
-                continue 'decode;
-            }
-            _ => {}
-        };
+        // Decoded from the start of the code segment, so offsets are the IP values
+        let code_start = exe.entry_offset() - exe.header.initial_ip as usize;
+        let relocations = exe
+            .relocation_offsets()
+            .range(code_start..)
+            .map(|offset| offset - code_start)
+            .collect();
+        // Symbols need absolute jump targets to be looked up
+        let origin = (!options.symbols.is_empty()).then_some(0);
+        let code_segment = exe.header.initial_cs;
+        let (labels, data_labels) = symbol_labels(&options.symbols, 0, Some(code_segment));
+        let mut formatter = Formatter::new(options.syntax, origin)
+            .with_labels(labels.clone())
+            .with_data_labels(data_labels);
+        let mut data = data::relative_to(&options.data, exe.image_start + code_start);
+        if let Some(project) = &options.project {
+            formatter = formatter.with_project(project, Some(code_segment), false);
+            data = merge_data(data, project.data_ranges(Some(code_segment), 0));
+        }
+        println!("bits 16");
+        decode_instructions(
+            &exe.image[code_start..],
+            exe.header.initial_ip as usize..exe.image.len() - code_start,
+            &relocations,
+            &data,
+            &options,
+            &formatter,
+        );
+    } else {
+        if options.info {
+            panic!("--info needs an MZ executable");
+        }
 
-        // Match 7 bit instructions
-        let opcode = byte1 >> 1;
-        match opcode {
-            0b1010000 => {
-                decode_mov_mem_acc(&mut bytes, true);
-                continue 'decode;
-            }
-            0b1010001 => {
-                decode_mov_mem_acc(&mut bytes, false);
-                continue 'decode;
-            }
-            0b1100011 => {
-                decode_imm_regmem("mov", &mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b0000010 | 0b0000110 | 0b0001010 | 0b0001110 | 0b0010010 | 0b0010110 | 0b0011010
-            | 0b0011110 => {
-                let inst_idx = (byte1 >> 3 & 0b0000111) as usize;
-                let inst_name = ALU_NAMES[inst_idx];
-                decode_imm_acc(inst_name, &mut bytes);
-                continue 'decode;
-            }
-            0b1000011 => {
-                decode_regmem_reg("xchg", &mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b1000010 => {
-                decode_regmem_reg("test", &mut bytes, arg1, arg2, &mut segment_override);
-                continue 'decode;
-            }
-            0b1010100 => {
-                decode_imm_acc("test", &mut bytes);
-                continue 'decode;
-            }
-            0b1110010 => {
-                let is_out = false;
-                let is_fixed = true;
-                decode_in_out(is_out, &mut bytes, arg1, arg2, is_fixed);
-                continue 'decode;
-            }
-            0b1110110 => {
-                let is_out = false;
-                let is_fixed = false;
-                decode_in_out(is_out, &mut bytes, arg1, arg2, is_fixed);
-                continue 'decode;
-            }
-            0b1110011 => {
-                let is_out = true;
-                let is_fixed = true;
-                decode_in_out(is_out, &mut bytes, arg1, arg2, is_fixed);
-                continue 'decode;
-            }
-            0b1110111 => {
-                let is_out = true;
-                let is_fixed = false;
-                decode_in_out(is_out, &mut bytes, arg1, arg2, is_fixed);
-                continue 'decode;
+        let range = byte_range(&options, file.len());
+        // A .COM file keeps its load offset, so a range out of it starts that much later
+        let origin = match options.origin {
+            Some(origin) => Some(origin),
+            None if options.com => Some(Origin {
+                segment: None,
+                offset: com::ORIGIN + range.start as u32,
+            }),
+            None => None,
+        };
+        let origin_offset = origin.map_or(0, |origin| origin.offset);
+        let (labels, data_labels) = symbol_labels(&options.symbols, origin_offset, None);
+        // Symbols need absolute jump targets to be looked up
+        let formatter_origin = match origin {
+            Some(origin) => Some(origin.offset),
+            None => (!options.symbols.is_empty()).then_some(0),
+        };
+        let mut formatter = Formatter::new(options.syntax, formatter_origin)
+            .with_labels(labels.clone())
+            .with_data_labels(data_labels);
+        let mut data = data::relative_to(&options.data, range.start);
+        if let Some(project) = &options.project {
+            formatter = formatter.with_project(project, None, true);
+            data = merge_data(data, project.data_ranges(None, origin_offset));
+        }
+        if options.exec {
+            // DOS would load a .COM file higher, clear of the interrupt vectors
+            let segment = match origin.and_then(|origin| origin.segment) {
+                Some(segment) => segment,
+                None if options.com => com::LOAD_SEGMENT,
+                None => 0,
+            };
+            let load = (segment, origin_offset as u16);
+            execute(&file[range], load, &options, &formatter);
+            return;
+        }
+        println!("bits 16");
+        if let Some(origin) = origin {
+            if let Some(segment) = origin.segment {
+                println!("; loaded at {segment:04x}:{:04x}", origin.offset);
             }
-            0b1111011 => {
-                let reg_idx = (bytes[1] >> GRP_INST_IDX_SHIFT & GRP_INST_IDX_MASK) as usize;
-                if reg_idx >= 2 {
-                    decode_unary_regmem(
-                        GRP1_NAMES[reg_idx],
-                        &mut bytes,
-                        arg1,
-                        &mut segment_override,
-                    );
-                } else {
-                    decode_imm_regmem("test", &mut bytes, arg1, arg2, &mut segment_override);
+            println!("org {}", nasm_hex(origin.offset));
+        }
+        decode_instructions(
+            &file[range.start..],
+            0..range.len(),
+            &BTreeSet::new(),
+            &data,
+            &options,
+            &formatter,
+        );
+    }
+}
+
+// Ranges from the command line and from the project file, which wins where they overlap
+fn merge_data(data: Vec<DataRange>, project_data: Vec<DataRange>) -> Vec<DataRange> {
+    let mut merged: Vec<DataRange> = data
+        .into_iter()
+        .filter(|range| {
+            !project_data
+                .iter()
+                .any(|other| other.start < range.end && range.start < other.end)
+        })
+        .collect();
+    merged.extend(project_data);
+    merged.sort_by_key(|range| range.start);
+    merged
+}
+
+// Loads the program at segment:offset, with every segment register pointing at that segment,
+// and runs it from there, decoding each instruction at CS:IP. Each one is printed with the flags
+// it changes, and the registers left are printed once the program executes hlt, IP leaves the
+// loaded bytes or the step limit is reached.
+fn execute(bytes: &[u8], load: (u16, u16), options: &Options, formatter: &Formatter) {
+    let (segment, origin) = load;
+    let mut memory = Memory::new();
+    memory.load(segment, origin, bytes);
+    let mut cpu = Cpu::new(segment, origin);
+    if options.com {
+        cpu.registers[SP] = com::INITIAL_SP;
+    }
+    cpu.rep_resume_bug = options.rep_resume_bug;
+    let mut dos = options.dos.then(DosServices::default);
+    let handler: &mut dyn InterruptHandler = match &mut dos {
+        Some(dos) => dos,
+        None => &mut VectorTable,
+    };
+    let mut line = String::with_capacity(128);
+
+    let image_start = Memory::physical(segment, origin);
+    let image = image_start..image_start + bytes.len();
+    let mut steps = 0;
+    let mut total_clocks = 0;
+    while !cpu.halted && image.contains(&Memory::physical(cpu.segments[CS], cpu.ip)) {
+        if steps == options.steps {
+            println!("; stopped after {steps} instructions");
+            break;
+        }
+        steps += 1;
+
+        let instruction = decode_instruction(
+            &memory.fetch(cpu.segments[CS], cpu.ip),
+            options.cpu_mode,
+        );
+        // Where the instruction lies relative to the origin, for the jump targets of the listing
+        let offset = cpu.ip.wrapping_sub(origin) as usize;
+        let before = cpu.clone();
+        cpu.ip = cpu.ip.wrapping_add(instruction.size as u16);
+        cpu.execute(&instruction, &mut memory, handler);
+        // The instruction is listed and the state before it is kept
+        if let Some(mnemonic) = cpu.unsupported {
+            cpu = before;
+            line.clear();
+            formatter.format(&instruction, offset, &mut line);
+            println!("{line} ; stopped: {mnemonic} is not supported by the simulator");
+            break;
+        }
+
+        // What the instruction did, after the instruction and separated by |
+        let mut changes = Vec::new();
+        if options.clocks {
+            let clocks = clocks::estimate(
+                &instruction,
+                &cpu.cycles,
+                options.cpu_mode == CpuMode::I8088,
+            );
+            total_clocks += clocks.total();
+            let mut text = format!("Clocks: +{} = {total_clocks}", clocks.total());
+            if clocks.total() != clocks.base {
+                write!(text, " ({clocks})").unwrap();
+            }
+            changes.push(text);
+        }
+        // Without --trace only the flags are shown
+        let mut state = String::new();
+        for change in cpu.changes(&before) {
+            if options.trace || matches!(change, Change::Flags(..)) {
+                if !state.is_empty() {
+                    state.push(' ');
                 }
-                continue 'decode;
-            }
-            0b1111111 => {
-                let reg_idx = (bytes[1] >> GRP_INST_IDX_SHIFT & GRP_INST_IDX_MASK) as usize;
-                decode_unary_regmem(GRP2_NAMES[reg_idx], &mut bytes, arg1, &mut segment_override);
-                continue 'decode;
+                formatter.write_change(&change, &mut state);
             }
-            0b1010010 => {
-                let w_bit = byte1 & W_BIT_MASK;
-                if w_bit == 1 {
-                    println!("movsw")
-                } else {
-                    println!("movsb")
-                };
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b1010011 => {
-                let w_bit = byte1 & W_BIT_MASK;
-                if w_bit == 1 {
-                    println!("cmpsw")
+        }
+        if !state.is_empty() {
+            changes.push(state);
+        }
+
+        line.clear();
+        formatter.format(&instruction, offset, &mut line);
+        if !changes.is_empty() {
+            write!(line, " ; {}", changes.join(" | ")).unwrap();
+        }
+        println!("{line}");
+    }
+    if let Some(dos) = dos
+        && !dos.output.is_empty()
+    {
+        println!("\nOutput:\n{}", String::from_utf8_lossy(&dos.output));
+    }
+    println!();
+    cpu.print_registers();
+}
+
+// Names of jump targets and of direct memory operands. origin is the address of the start of the
+// NASM section, code_segment the load-relative code segment of an MZ executable, whose symbols
+// name code while the ones in other segments name data.
+fn symbol_labels(
+    symbols: &[Symbol],
+    origin: u32,
+    code_segment: Option<u16>,
+) -> (BTreeMap<u32, String>, BTreeMap<u32, String>) {
+    let mut labels = BTreeMap::new();
+    let mut data_labels = BTreeMap::new();
+    for symbol in symbols {
+        let name = symbol.name.clone();
+        match symbol.address {
+            SymbolAddress::SegOff(segment, offset) if code_segment.is_some() => {
+                if Some(segment) == code_segment {
+                    labels.insert(offset as u32, name);
                 } else {
-                    println!("cmpsb")
-                };
-                bytes = &bytes[1..];
-                continue 'decode;
+                    data_labels.insert(offset as u32, name);
+                }
             }
-            0b1010111 => {
-                let w_bit = byte1 & W_BIT_MASK;
-                if w_bit == 1 {
-                    println!("scasw")
-                } else {
-                    println!("scasb")
-                };
-                bytes = &bytes[1..];
-                continue 'decode;
+            SymbolAddress::SegOff(segment, offset) => {
+                let address = segment as u32 * 16 + offset as u32;
+                labels.insert(address, name.clone());
+                data_labels.insert(address, name);
             }
-            0b1010110 => {
-                let w_bit = byte1 & W_BIT_MASK;
-                if w_bit == 1 {
-                    println!("lodsw")
-                } else {
-                    println!("lodsb")
-                };
-                bytes = &bytes[1..];
-                continue 'decode;
+            SymbolAddress::Linear(address) => {
+                labels.insert(address, name.clone());
+                data_labels.insert(address, name);
             }
-            0b1010101 => {
-                let w_bit = byte1 & W_BIT_MASK;
-                if w_bit == 1 {
-                    println!("stosw")
-                } else {
-                    println!("stosb")
-                };
-                bytes = &bytes[1..];
-                continue 'decode;
+            SymbolAddress::SectionOffset(offset) => {
+                labels.insert(origin + offset, name.clone());
+                data_labels.insert(origin + offset, name);
             }
-            _ => (),
         }
+    }
+    (labels, data_labels)
+}
 
-        // match 8 bit instruction
-        match byte1 {
-            0b01110000..=0b01111111 => {
-                decode_jmp_and_loops(&mut bytes, true);
-                continue 'decode;
-            }
-            0b11100000..=0b11100011 => {
-                decode_jmp_and_loops(&mut bytes, false);
-                continue 'decode;
-            }
-            0b10001111 => {
-                decode_unary_regmem("pop", &mut bytes, arg1, &mut segment_override);
-                continue 'decode;
-            }
-            0b00000110 | 0b00001110 | 0b00010110 | 0b00011110 => {
-                decode_push_pop_seg("push", &mut bytes);
-                continue 'decode;
-            }
-            0b00000111 | 0b00001111 | 0b00010111 | 0b00011111 => {
-                decode_push_pop_seg("pop", &mut bytes);
-                continue 'decode;
-            }
-            0b11010111 => {
-                println!("xlat");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011111 => {
-                println!("lahf");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011110 => {
-                println!("sahf");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011100 => {
-                println!("pushf");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011101 => {
-                println!("popf");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10001101 => {
-                decode_load_ptr("lea", &mut bytes, arg1, &mut segment_override);
-                continue 'decode;
-            }
-            0b11000101 => {
-                decode_load_ptr("lds", &mut bytes, arg1, &mut segment_override);
-                continue 'decode;
-            }
-            0b11000100 => {
-                decode_load_ptr("les", &mut bytes, arg1, &mut segment_override);
-                continue 'decode;
-            }
-            0b00110111 => {
-                println!("aaa");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b00100111 => {
-                println!("daa");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b00111111 => {
-                println!("aas");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b00101111 => {
-                println!("das");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11010100 => {
-                println!("aam");
-                bytes = &bytes[2..];
-                continue 'decode;
-            }
-            0b11010101 => {
-                println!("aad");
-                bytes = &bytes[2..];
-                continue 'decode;
-            }
-            0b10011000 => {
-                println!("cbw");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011001 => {
-                println!("cwd");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11110011 => {
-                print!("rep ");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11110010 => {
-                print!("repnz ");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11000010 => {
-                decode_unary_imm("ret", &mut bytes);
-                continue 'decode;
-            }
-            0b11001010 => {
-                decode_unary_imm("retf", &mut bytes);
-                continue 'decode;
-            }
-            0b11000011 => {
-                println!("ret");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11001011 => {
-                println!("retf");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11001101 => {
-                println!("int {}", bytes[1]);
-                bytes = &bytes[2..];
-            }
-            0b11001100 => {
-                println!("int3");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11001110 => {
-                println!("into");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11001111 => {
-                println!("iret");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111000 => {
-                println!("clc");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11110101 => {
-                println!("cmc");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111001 => {
-                println!("stc");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111100 => {
-                println!("cld");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111101 => {
-                println!("std");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111010 => {
-                println!("cli");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11111011 => {
-                println!("sti");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b11110100 => {
-                println!("hlt");
-                bytes = &bytes[1..];
-                continue 'decode;
-            }
-            0b10011011 => {
-                println!("wait");
-                bytes = &bytes[1..];
-                continue 'decode;
+// The file offsets selected by --start and --end or --length, the whole file by default
+fn byte_range(options: &Options, file_len: usize) -> Range<usize> {
+    let start = options.start.unwrap_or(0);
+    let end = match (options.end, options.length) {
+        (Some(end), _) => end,
+        (None, Some(length)) => start + length,
+        (None, None) => file_len,
+    };
+    if end > file_len {
+        panic!(
+            "The range {start:#x}..{end:#x} ends past the end of the file ({file_len:#x} bytes)"
+        );
+    }
+    if start > end {
+        panic!("The range {start:#x}..{end:#x} ends before it starts");
+    }
+    start..end
+}
+
+// Decimal, 0x-prefixed or h-suffixed hex
+pub fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_suffix(['h', 'H']) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// Hex digit pairs, optionally separated by spaces or commas and prefixed with 0x
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for token in text.split([' ', ',']).filter(|token| !token.is_empty()) {
+        let digits = token.strip_prefix("0x").unwrap_or(token);
+        if digits.is_empty() || !digits.len().is_multiple_of(2) {
+            return None;
+        }
+        for idx in (0..digits.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(digits.get(idx..idx + 2)?, 16).ok()?);
+        }
+    }
+    (!bytes.is_empty()).then_some(bytes)
+}
+
+// Either a plain address or seg:off, where both halves are hex as in debuggers
+fn parse_origin(text: &str) -> Option<Origin> {
+    match text.split_once(':') {
+        Some((segment, offset)) => {
+            let hex = |text: &str| {
+                let text = text.strip_prefix("0x").unwrap_or(text);
+                u16::from_str_radix(text.strip_suffix(['h', 'H']).unwrap_or(text), 16).ok()
+            };
+            Some(Origin {
+                segment: Some(hex(segment)?),
+                offset: hex(offset)? as u32,
+            })
+        }
+        None => Some(Origin {
+            segment: None,
+            offset: parse_number(text)?,
+        }),
+    }
+}
+
+// NASM's h suffix form, which needs a leading digit
+fn nasm_hex(value: u32) -> String {
+    let hex = format!("{value:X}");
+    if hex.starts_with(|digit: char| digit.is_ascii_digit()) {
+        format!("{hex}h")
+    } else {
+        format!("0{hex}h")
+    }
+}
+
+fn parse_args(args: &[String]) -> Options {
+    const USAGE: &str = "Usage: ./sim8086 [--cpu 8086|8088|v20|v30|386] [--syntax intel|nec] [--input-format bin|ihex|srec] [--info] [--com|--rom|--boot] [--start N] [--end N|--length N] [--origin N|seg:off] [--data start:end[:db|dw|dd|ptr|farptr|str]]... [--data-file path] [--symbols file.map] [--project file.json] [--exec|--trace [--steps N] [--clocks] [--dos] [--rep-resume-bug]] (path/to/binary/file | - | --hex \"89 d9\")";
+
+    let mut input = None;
+    let mut cpu_mode = CpuMode::I8086;
+    let mut syntax = Syntax::Intel;
+    let mut info = false;
+    let mut com = false;
+    let mut input_format = None;
+    let mut rom = false;
+    let mut boot = false;
+    let mut start = None;
+    let mut end = None;
+    let mut length = None;
+    let mut origin = None;
+    let mut data = Vec::new();
+    let mut symbols = Vec::new();
+    let mut project = None;
+    let mut exec = false;
+    let mut steps = DEFAULT_STEPS;
+    let mut clocks = false;
+    let mut trace = false;
+    let mut dos = false;
+    let mut rep_resume_bug = false;
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cpu" => {
+                cpu_mode = match args.next().map(String::as_str) {
+                    Some("8086") => CpuMode::I8086,
+                    Some("8088") => CpuMode::I8088,
+                    Some("v20" | "v30") => CpuMode::V20,
+                    Some("386") => CpuMode::I386,
+                    _ => panic!("{USAGE}"),
+                }
             }
-            0b11110000 => {
-                print!("lock ");
-                bytes = &bytes[1..];
-                continue 'decode;
+            "--syntax" => {
+                syntax = match args.next().map(String::as_str) {
+                    Some("intel") => Syntax::Intel,
+                    Some("nec") => Syntax::Nec,
+                    _ => panic!("{USAGE}"),
+                }
             }
-            0b00100110 => {
-                segment_override = SegOverrideReg(Some("es:"));
-                bytes = &bytes[1..];
-                continue 'decode;
+            "--input-format" => {
+                input_format = match args.next().map(String::as_str) {
+                    Some("bin") => Some(InputFormat::Binary),
+                    Some("ihex") => Some(InputFormat::IntelHex),
+                    Some("srec") => Some(InputFormat::SRecord),
+                    _ => panic!("{USAGE}"),
+                }
             }
-            0b00101110 => {
-                segment_override = SegOverrideReg(Some("cs:"));
-                bytes = &bytes[1..];
-                continue 'decode;
+            "--info" => info = true,
+            "--com" => com = true,
+            "--rom" => rom = true,
+            "--boot" => boot = true,
+            "--exec" => exec = true,
+            "--trace" => {
+                exec = true;
+                trace = true;
+            }
+            "--clocks" => clocks = true,
+            "--dos" => dos = true,
+            "--rep-resume-bug" => rep_resume_bug = true,
+            "--steps" => {
+                let value = args.next().and_then(|value| parse_number(value));
+                steps = value.expect(USAGE) as usize;
+            }
+            "--start" | "--end" | "--length" => {
+                let value = args.next().and_then(|value| parse_number(value));
+                let value = Some(value.expect(USAGE) as usize);
+                match arg.as_str() {
+                    "--start" => start = value,
+                    "--end" => end = value,
+                    _ => length = value,
+                }
             }
-            0b00110110 => {
-                segment_override = SegOverrideReg(Some("ss:"));
-                bytes = &bytes[1..];
-                continue 'decode;
+            "--data" => data.push(
+                args.next()
+                    .and_then(|value| data::parse_range(value))
+                    .expect(USAGE),
+            ),
+            "--data-file" => {
+                let path = args.next().expect(USAGE);
+                let text = fs::read_to_string(path).expect("Could not read data range file");
+                data.extend(data::parse_range_file(&text));
+            }
+            "--symbols" => {
+                let path = args.next().expect(USAGE);
+                let text = fs::read(path).expect("Could not read symbol file");
+                symbols.extend(symbols::parse(&String::from_utf8_lossy(&text)));
+            }
+            "--project" => {
+                let path = args.next().expect(USAGE);
+                let text = fs::read_to_string(path).expect("Could not read project file");
+                project = Some(Project::parse(&text));
+            }
+            "--origin" => {
+                origin = Some(
+                    args.next()
+                        .and_then(|value| parse_origin(value))
+                        .expect(USAGE),
+                )
+            }
+            "--hex" if input.is_none() => {
+                let bytes = args.next().and_then(|value| parse_hex_bytes(value));
+                input = Some(Input::Bytes(bytes.expect(USAGE)))
+            }
+            "-" if input.is_none() => input = Some(Input::Stdin),
+            _ if input.is_none() => input = Some(Input::File(arg.clone())),
+            _ => panic!("{USAGE}"),
+        }
+    }
+
+    // The load modes exclude each other, --info is only for MZ executables
+    if [com, rom, boot, info].iter().filter(|&&mode| mode).count() > 1
+        || (end.is_some() && length.is_some())
+    {
+        panic!("{USAGE}");
+    }
+
+    let input = input.expect(USAGE);
+    // A project file next to the input is picked up without being named
+    if project.is_none()
+        && let Input::File(path) = &input
+        && let Ok(text) = fs::read_to_string(format!("{path}.sim8086.json"))
+    {
+        project = Some(Project::parse(&text));
+    }
+
+    Options {
+        input,
+        cpu_mode,
+        syntax,
+        info,
+        com,
+        input_format,
+        rom,
+        boot,
+        start,
+        end,
+        length,
+        origin,
+        data,
+        symbols,
+        project,
+        exec,
+        steps,
+        clocks,
+        trace,
+        dos,
+        rep_resume_bug,
+    }
+}
+
+// relocations holds the offsets of segment words that the loader patches, data the sorted
+// ranges that are written as data instead
+// Near the end of the bytes they are padded with zeros, so that an instruction running past the
+// end is decoded without reading beyond it. Its size tells the caller it does not fit.
+fn decode_before(bytes: &[u8], cpu_mode: CpuMode) -> Instruction {
+    if bytes.len() >= MAX_INSTRUCTION_SIZE {
+        return decode_instruction(bytes, cpu_mode);
+    }
+    decode_instruction(&[bytes, &[0; MAX_INSTRUCTION_SIZE]].concat(), cpu_mode)
+}
+
+fn decode_instructions(
+    bytes: &[u8],
+    range: Range<usize>,
+    relocations: &BTreeSet<usize>,
+    data: &[DataRange],
+    options: &Options,
+    formatter: &Formatter,
+) {
+    // Reused for every instruction, like an arena
+    let mut line = String::with_capacity(128);
+
+    // The offset is advanced by the decoded size because different instructions have different lengths
+    let mut offset = range.start;
+    let mut data = data.iter().peekable();
+    while offset < range.end {
+        if let Some(label) = formatter.label(offset) {
+            println!("{label}:");
+        }
+        if let Some(comment) = formatter.comment(offset) {
+            println!("; {comment}");
+        }
+        while let Some(next) = data.peek()
+            && next.end <= offset
+        {
+            data.next();
+        }
+        if let Some(next) = data.peek()
+            && next.start <= offset
+        {
+            // Split at labels and comments inside the range so they are not lost
+            let end = next
+                .end
+                .min(range.end)
+                .min(formatter.next_annotation(offset).unwrap_or(usize::MAX));
+            data::print_data(&bytes[offset..end], next.kind);
+            offset = end;
+            continue;
+        }
+
+        let instruction = decode_before(&bytes[offset..range.end], options.cpu_mode);
+        // Bytes in front of a data range or the end that do not form a whole instruction stay bytes
+        let limit = data.peek().map_or(range.end, |next| next.start.min(range.end));
+        if offset + instruction.size > limit {
+            data::print_data(&bytes[offset..limit], DataKind::Bytes);
+            offset = limit;
+            continue;
+        }
+
+        line.clear();
+        formatter.format(&instruction, offset, &mut line);
+        if relocations
+            .range(offset..offset + instruction.size)
+            .next()
+            .is_some()
+        {
+            line.push_str(" ; relocated segment");
+        }
+        if options.com
+            && let Some((address, field)) = instruction.operands.iter().find_map(psp_reference)
+        {
+            write!(line, " ; PSP+{address:#04x} {field}").unwrap();
+        }
+        println!("{line}");
+
+        offset += instruction.size;
+    }
+}
+
+// Each region is decoded at its load address, the holes between them are only reported
+fn decode_regions(regions: &[Region], options: &Options) {
+    let mut previous_end = None;
+    for (number, region) in regions.iter().enumerate() {
+        if let Some(end) = previous_end {
+            println!("; gap of {} bytes at {end:#07x}", region.address - end);
+        }
+        println!("; {} bytes at {:#07x}", region.bytes.len(), region.address);
+
+        // Each region gets a segment of its own 64 KiB block, so that its near jumps and
+        // addresses are offsets, and a section that puts it back at its place in the image
+        let segment = ((region.address >> 16) << 12) as u16;
+        let offset = region.address & 0xFFFF;
+        println!("; loaded at {segment:04x}:{offset:04x}");
+        println!(
+            "section region{number} start={} vstart={}",
+            nasm_hex(region.address),
+            nasm_hex(offset)
+        );
+
+        let mut formatter = Formatter::new(options.syntax, Some(offset));
+        let mut data = Vec::new();
+        if let Some(project) = &options.project {
+            formatter = formatter.with_project(project, Some(segment), true);
+            data = project.data_ranges(Some(segment), offset);
+        }
+        decode_instructions(
+            &region.bytes,
+            0..region.bytes.len(),
+            &BTreeSet::new(),
+            &data::relative_to(&data, 0),
+            options,
+            &formatter,
+        );
+        previous_end = Some(region.address + region.bytes.len() as u32);
+    }
+}
+
+// Every code segment is decoded on its own, entry points become labels and relocations are
+// shown as the imported or internal names they resolve to
+fn decode_new_executable(exe: &NeExecutable, options: &Options) {
+    println!("bits 16");
+    println!("; NE module {}, {}", exe.module_name, exe.description);
+
+    for segment in &exe.segments {
+        println!();
+        let kind = if segment.is_data { "data" } else { "code" };
+        println!(
+            "; segment {} ({kind}), {} bytes",
+            segment.number,
+            segment.data.len()
+        );
+        let number = segment.number as u16;
+        if segment.is_data {
+            let mut formatter = Formatter::new(options.syntax, Some(0));
+            let mut data = Vec::new();
+            if let Some(project) = &options.project {
+                formatter = formatter.with_project(project, Some(number), true);
+                data = project.data_ranges(Some(number), 0);
+            }
+            decode_instructions(
+                &segment.data,
+                0..segment.data.len(),
+                &BTreeSet::new(),
+                &data::fill_gaps(&data::relative_to(&data, 0), segment.data.len()),
+                options,
+                &formatter,
+            );
+            continue;
+        }
+
+        let mut labels: BTreeMap<u32, String> = segment
+            .labels
+            .iter()
+            .map(|(&offset, name)| (offset as u32, name.clone()))
+            .collect();
+        let (entry_segment, entry_ip) = exe.entry;
+        if entry_segment == segment.number {
+            labels
+                .entry(entry_ip as u32)
+                .or_insert_with(|| "start".to_string());
+        }
+        let mut formatter = Formatter::new(options.syntax, Some(0))
+            .with_labels(labels.clone())
+            .with_fixups(segment.fixups.clone());
+        let mut data = Vec::new();
+        if let Some(project) = &options.project {
+            formatter = formatter.with_project(project, Some(number), false);
+            data = project.data_ranges(Some(number), 0);
+        }
+        decode_instructions(
+            &segment.data,
+            0..segment.data.len(),
+            &BTreeSet::new(),
+            &data::relative_to(&data, 0),
+            options,
+            &formatter,
+        );
+    }
+}
+
+// Written in NASM's obj syntax: externs, publics and groups first, then every segment with
+// its public and local names as labels and fixups as symbolic references
+fn decode_object_module(module: &ObjectModule, options: &Options) {
+    println!("bits 16");
+    println!("; module {}", module.name);
+    for name in &module.externs {
+        println!("extern {name}");
+    }
+    for segment in &module.segments {
+        for name in segment.publics.values() {
+            println!("global {name}");
+        }
+    }
+    for (name, segments) in &module.groups {
+        let segment_names: Vec<&str> = segments
+            .iter()
+            .map(|&idx| module.segments[idx].name.as_str())
+            .collect();
+        println!("group {name} {}", segment_names.join(" "));
+    }
+
+    for (idx, segment) in module.segments.iter().enumerate() {
+        println!();
+        println!("segment {} class={}", segment.name, segment.class);
+        let labels: BTreeMap<u32, String> = segment
+            .publics
+            .iter()
+            .chain(&segment.locals)
+            .map(|(&offset, name)| (offset as u32, name.clone()))
+            .collect();
+        let is_code = segment.class.to_ascii_uppercase().contains("CODE");
+        // Segments are numbered from 1 in the project file, as in the records
+        let number = Some(idx as u16 + 1);
+        let mut formatter = Formatter::new(options.syntax, Some(0))
+            .with_labels(labels.clone())
+            .with_fixups(segment.fixups.clone());
+        let mut data = Vec::new();
+        if let Some(project) = &options.project {
+            formatter = formatter.with_project(project, number, !is_code);
+            data = data::relative_to(&project.data_ranges(number, 0), 0);
+        }
+
+        if !segment.initialized {
+            for name in labels.values() {
+                println!("{name}:");
+            }
+            println!("resb {}", segment.data.len());
+        } else if is_code {
+            decode_instructions(
+                &segment.data,
+                0..segment.data.len(),
+                &BTreeSet::new(),
+                &data,
+                options,
+                &formatter,
+            );
+        } else {
+            print_object_data(segment, &data, &formatter);
+        }
+    }
+}
+
+// Data segments as bytes or the declared data types, split at the labels, with the fixed-up
+// words written by name
+fn print_object_data(segment: &omf::Segment, data: &[DataRange], formatter: &Formatter) {
+    let mut offset = 0;
+    while offset < segment.data.len() {
+        if let Some(name) = formatter.label(offset) {
+            println!("{name}:");
+        }
+        if let Some(comment) = formatter.comment(offset) {
+            println!("; {comment}");
+        }
+        if let Some(fixup) = segment.fixups.get(&offset) {
+            let (directive, size) = match fixup.kind {
+                format::FixupKind::LowByte | format::FixupKind::HighByte => ("db", 1),
+                format::FixupKind::Pointer => ("dd", 4),
+                _ => ("dw", 2),
+            };
+            let addend = match size {
+                1 => segment.data[offset] as i32,
+                _ => u16::from_le_bytes([segment.data[offset], segment.data[offset + 1]]) as i32,
+            };
+            let mut line = format!("{directive} ");
+            format::write_symbol(&mut line, fixup, addend);
+            println!("{line}");
+            offset += size;
+            continue;
+        }
+
+        let range = data
+            .iter()
+            .find(|range| range.start <= offset && offset < range.end);
+        let end = segment
+            .fixups
+            .range(offset + 1..)
+            .map(|(&start, _)| start)
+            .chain(formatter.next_annotation(offset))
+            .chain(range.map(|range| range.end))
+            .chain(
+                data.iter()
+                    .map(|range| range.start)
+                    .filter(|&start| start > offset),
+            )
+            .min()
+            .unwrap_or(segment.data.len())
+            .min(segment.data.len());
+        let kind = range.map_or(DataKind::Bytes, |range| range.kind);
+        data::print_data(&segment.data[offset..end], kind);
+        offset = end;
+    }
+}
+
+// The initial jump and the BPB behind it are written as they are, the code is decoded from the
+// jump target up to the signature
+fn decode_boot_sector(file: &[u8], options: &Options) {
+    if file.len() < boot::SECTOR_SIZE {
+        panic!("A boot sector needs {} bytes", boot::SECTOR_SIZE);
+    }
+    let sector = &file[..boot::SECTOR_SIZE];
+    let mut formatter = Formatter::new(options.syntax, Some(boot::ORIGIN));
+    let mut data = data::relative_to(&options.data, 0);
+    if let Some(project) = &options.project {
+        formatter = formatter.with_project(project, None, true);
+        data = merge_data(data, project.data_ranges(None, boot::ORIGIN));
+    }
+
+    println!("bits 16");
+    println!("org {}", nasm_hex(boot::ORIGIN));
+    let code_start = match boot::initial_jump(sector) {
+        Some((jump_size, target)) => {
+            decode_instructions(
+                sector,
+                0..jump_size,
+                &BTreeSet::new(),
+                &[],
+                options,
+                &formatter,
+            );
+            boot::print_bpb(sector, jump_size, target);
+            target
+        }
+        None => 0,
+    };
+    decode_instructions(
+        &sector[..boot::SIGNATURE_OFFSET],
+        code_start..boot::SIGNATURE_OFFSET,
+        &BTreeSet::new(),
+        &data,
+        options,
+        &formatter,
+    );
+
+    let signature = &sector[boot::SIGNATURE_OFFSET..];
+    if boot::has_signature(sector) {
+        println!("dw 0xaa55 ; boot signature");
+    } else {
+        let mut line = String::new();
+        format::write_db(&mut line, signature);
+        println!("{line} ; boot signature 0x55 0xaa missing");
+    }
+}
+
+// Follows the control flow from the entry point instead of sweeping the whole image, ROMs mix
+// code with tables and strings. The listing is in address order with seg:off addresses.
+fn decode_reachable(rom: &Rom, options: &Options) {
+    let mut formatter = Formatter::new(options.syntax, Some(rom.base as u32));
+    // Image offset -> instruction size and text
+    let mut listing = BTreeMap::new();
+
+    // Data ranges of the project file are listed as they are and never decoded
+    let mut data = Vec::new();
+    if let Some(project) = &options.project {
+        formatter = formatter.with_project(project, Some(rom.segment), true);
+        data = project.data_ranges(Some(rom.segment), rom.base as u32);
+    }
+    for range in &data {
+        let end = range.end.min(rom.image.len());
+        if range.start < end {
+            let lines = data::data_lines(&rom.image[range.start..end], range.kind);
+            listing.insert(
+                range.start,
+                (end - range.start, lines.join("\n           ")),
+            );
+        }
+    }
+    let is_data = |offset: usize| {
+        data.iter()
+            .any(|range| range.start <= offset && offset < range.end)
+    };
+
+    let mut pending = vec![rom.entry];
+    while let Some(mut offset) = pending.pop() {
+        while offset < rom.image.len() && !listing.contains_key(&offset) && !is_data(offset) {
+            let instruction = decode_before(&rom.image[offset..], options.cpu_mode);
+            let next = offset + instruction.size;
+            // Code running into the end of the image leaves its last bytes as data
+            if next > rom.image.len() {
+                let lines = data::data_lines(&rom.image[offset..], DataKind::Bytes);
+                listing.insert(offset, (rom.image.len() - offset, lines.join("\n           ")));
+                break;
             }
-            0b00111110 => {
-                segment_override = SegOverrideReg(Some("ds:"));
-                bytes = &bytes[1..];
-                continue 'decode;
+
+            let mut line = String::new();
+            formatter.format(&instruction, offset, &mut line);
+            listing.insert(offset, (instruction.size, line));
+
+            if let Some(target) = branch_target(&instruction, rom, next) {
+                pending.push(target);
             }
-            0b10011010 => {
-                decode_direct_intersegment("call", &mut bytes);
-                continue 'decode;
+            if ends_flow(instruction.mnemonic) {
+                break;
             }
-            0b11101010 => {
-                decode_direct_intersegment("jmp", &mut bytes);
-                continue 'decode;
+            offset = next;
+        }
+    }
+
+    let mut previous_end = 0;
+    for (&offset, (size, line)) in &listing {
+        if previous_end < offset {
+            println!("; {} bytes not reached", offset - previous_end);
+        }
+        if let Some(label) = formatter.label(offset) {
+            println!("{label}:");
+        }
+        if let Some(comment) = formatter.comment(offset) {
+            println!("; {comment}");
+        }
+        println!(
+            "{:04x}:{:04x}  {line}",
+            rom.segment,
+            rom.base as usize + offset
+        );
+        previous_end = offset + size;
+    }
+    if previous_end < rom.image.len() {
+        println!("; {} bytes not reached", rom.image.len() - previous_end);
+    }
+}
+
+// Image offset of a jump or call destination within the ROM, next is the offset after the instruction
+fn branch_target(instruction: &Instruction, rom: &Rom, next: usize) -> Option<usize> {
+    match instruction.operands.first()? {
+        Operand::Rel(disp, _) => {
+            // IP wraps around within the segment
+            let target = (rom.base as i64 + next as i64 + *disp as i64) as u16;
+            rom.image_offset(rom.segment, target)
+        }
+        Operand::Far(segment, offset) => rom.image_offset(*segment, *offset as u16),
+        _ => None,
+    }
+}
+
+fn ends_flow(mnemonic: &str) -> bool {
+    matches!(mnemonic, "jmp" | "ret" | "retf" | "iret" | "iretd" | "hlt")
+}
+
+// Direct memory operands below the load offset of a .COM program point into its PSP
+fn psp_reference(operand: &Operand) -> Option<(u16, &'static str)> {
+    match *operand {
+        Operand::Mem {
+            address: EffectiveAddress::Direct(address),
+            ..
+        } => com::psp_field(address).map(|field| (address, field)),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Prefixes {
+    lock: bool,
+    rep: Option<u8>,
+    segment: Option<usize>,
+    // 386 operand- and address-size prefixes (0x66, 0x67)
+    operand_size: bool,
+    address_size: bool,
+    // Every prefix byte in the order of the instruction. The fields above hold the last one of
+    // each kind, which is the one the CPU uses.
+    bytes: Vec<u8>,
+    // Number of leading bytes that are written as db because the prefixes in the listing would
+    // not assemble to them
+    stray: usize,
+}
+
+impl Prefixes {
+    // Word sized operands become dword sized behind the operand-size prefix
+    fn width(&self, w_bit: usize) -> usize {
+        if w_bit == 1 && self.operand_size {
+            2
+        } else {
+            w_bit
+        }
+    }
+
+    // Number of prefix bytes in front of the opcode
+    fn count(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Whether the listing writes the prefixes as rep, lock, segment overrides, o32 and a32. If not,
+    // they are all stray bytes.
+    fn spelled_out(&self) -> bool {
+        self.stray < self.bytes.len()
+    }
+}
+
+// The order NASM writes prefixes in: rep, lock, segment, operand size, address size
+fn prefix_group(prefix: u8) -> usize {
+    match prefix {
+        REPNE_PREFIX | REP_PREFIX => 0,
+        LOCK_PREFIX => 1,
+        0b01100110 => 3,
+        0b01100111 => 4,
+        _ => 2,
+    }
+}
+
+// The prefixes at the end that are in NASM's order, one of each kind, are written in the listing
+// and the ones in front of them as db. A repeated prefix only counts the last time, as for the
+// CPU. When one that counts is not in the run at the end, every prefix is written as db.
+fn stray_prefixes(prefixes: &[u8]) -> usize {
+    let mut kept = 0;
+    let mut groups = Vec::new();
+    for &prefix in prefixes.iter().rev() {
+        let group = prefix_group(prefix);
+        if groups.last().is_some_and(|&last| group >= last) {
+            break;
+        }
+        groups.push(group);
+        kept += 1;
+    }
+
+    let stray = &prefixes[..prefixes.len() - kept];
+    if stray
+        .iter()
+        .any(|&prefix| !groups.contains(&prefix_group(prefix)))
+    {
+        prefixes.len()
+    } else {
+        stray.len()
+    }
+}
+
+struct Instruction {
+    prefixes: Prefixes,
+    mnemonic: &'static str,
+    operands: Vec<Operand>,
+    // Only set for the indirect intersegment call and jmp
+    far: bool,
+    // The immediate is a full word or dword although it fits in a byte, so it is written with
+    // strict to keep NASM from using the short form
+    wide_immediate: bool,
+    // Number of bytes the instruction occupies, including prefixes
+    size: usize,
+}
+
+impl Instruction {
+    fn new(mnemonic: &'static str, operands: Vec<Operand>) -> Self {
+        Instruction {
+            prefixes: Prefixes::default(),
+            mnemonic,
+            operands,
+            far: false,
+            wide_immediate: false,
+            size: 0,
+        }
+    }
+
+    // An encoding the CPU does not define, kept as its bytes
+    fn invalid(encoding: &[u8]) -> Self {
+        let bytes = encoding.iter().map(|&byte| Operand::Imm(byte as i32)).collect();
+        Instruction::new("db", bytes)
+    }
+
+    // ESC hands the instruction to a coprocessor, the listing keeps it as bytes
+    fn coprocessor(encoding: &[u8]) -> Self {
+        Instruction {
+            mnemonic: "esc",
+            ..Instruction::invalid(encoding)
+        }
+    }
+
+    fn is_string_op(&self) -> bool {
+        self.compares_strings()
+            || matches!(
+                self.mnemonic,
+                "movsb"
+                    | "movsw"
+                    | "movsd"
+                    | "insb"
+                    | "insw"
+                    | "insd"
+                    | "outsb"
+                    | "outsw"
+                    | "outsd"
+                    | "lodsb"
+                    | "lodsw"
+                    | "lodsd"
+                    | "stosb"
+                    | "stosw"
+                    | "stosd"
+            )
+    }
+
+    // Only these end a repeat early depending on ZF, which is what repe/repne test
+    fn compares_strings(&self) -> bool {
+        matches!(
+            self.mnemonic,
+            "cmpsb" | "cmpsw" | "cmpsd" | "scasb" | "scasw" | "scasd"
+        )
+    }
+
+    // The memory operand is the destination, xchg writes both of its operands
+    fn is_lockable(&self) -> bool {
+        let writes_memory = match &self.operands[..] {
+            [Operand::Mem { .. }, ..] => true,
+            [_, Operand::Mem { .. }] => self.mnemonic == "xchg",
+            _ => false,
+        };
+        LOCKABLE_NAMES.contains(&self.mnemonic) && writes_memory
+    }
+}
+
+// Widths index the columns of REGISTER_MAP: 0 is a byte, 1 a word and 2 a dword
+enum Operand {
+    Reg(usize, usize), // register index, width
+    SegReg(usize),
+    Mem {
+        address: EffectiveAddress,
+        width: usize,
+        // Whether a size keyword is needed because no other operand implies the size
+        sized: bool,
+    },
+    Imm(i32),
+    Rel(i32, usize), // displacement from the end of the instruction, width of the displacement
+    Far(u16, u32),   // segment, offset
+}
+
+fn decode_instruction(stream: &[u8], cpu_mode: CpuMode) -> Instruction {
+    let mut bytes = stream;
+    let mut prefixes = Prefixes::default();
+
+    loop {
+        match bytes[0] {
+            LOCK_PREFIX => prefixes.lock = true,
+            REPNE_PREFIX | REP_PREFIX => prefixes.rep = Some(bytes[0]),
+            0b00100110 | 0b00101110 | 0b00110110 | 0b00111110 => {
+                prefixes.segment = Some(((bytes[0] >> 3) & 0b11) as usize)
             }
-            0b11101001 => {
-                decode_jmp_and_call_long_offset("jmp", &mut bytes);
-                continue 'decode;
+            0b01100100 | 0b01100101 if cpu_mode == CpuMode::I386 => {
+                prefixes.segment = Some(i386::FS_IDX + (bytes[0] & 1) as usize)
             }
-            0b11101000 => {
-                decode_jmp_and_call_long_offset("call", &mut bytes);
-                continue 'decode;
+            0b01100110 if cpu_mode == CpuMode::I386 => prefixes.operand_size = true,
+            0b01100111 if cpu_mode == CpuMode::I386 => prefixes.address_size = true,
+            _ => break,
+        }
+        prefixes.bytes.push(bytes[0]);
+        bytes = &bytes[1..];
+    }
+    prefixes.stray = stray_prefixes(&prefixes.bytes);
+
+    let mut instruction = decode_opcode(&mut bytes, cpu_mode, &prefixes);
+    // Behind size prefixes written as db, NASM would assemble the instruction at its default
+    // size and add its own prefix, so the instruction is kept as bytes too
+    if !prefixes.spelled_out() && (prefixes.operand_size || prefixes.address_size) {
+        let size = stream.len() - bytes.len();
+        instruction = Instruction::invalid(&stream[prefixes.count()..size]);
+    }
+    instruction.prefixes = prefixes;
+    instruction.size = stream.len() - bytes.len();
+    instruction
+}
+
+fn decode_opcode(bytes: &mut &[u8], cpu_mode: CpuMode, prefixes: &Prefixes) -> Instruction {
+    let byte1 = bytes[0];
+
+    // Match 4 bit instructions
+    let opcode = byte1 >> 4;
+    if opcode == 0b1011 {
+        return decode_mov_imm_reg(bytes, prefixes);
+    }
+
+    // Match 5 bit instructions
+    let opcode = byte1 >> 3;
+    match opcode {
+        0b01010 => return decode_one_byte_reg("push", bytes, prefixes),
+        0b01011 => return decode_one_byte_reg("pop", bytes, prefixes),
+        0b01000 => return decode_one_byte_reg("inc", bytes, prefixes),
+        0b01001 => return decode_one_byte_reg("dec", bytes, prefixes),
+        0b10010 => return decode_xchg_acc(bytes, prefixes),
+        _ => {}
+    };
+
+    // Match 6 bit instructions
+    let opcode = byte1 >> 2;
+    match opcode {
+        0b100000 => return decode_alu_imm_regmem(bytes, prefixes),
+        0b100010 => return decode_regmem_reg("mov", bytes, prefixes),
+        0b000000 | 0b000010 | 0b000100 | 0b000110 | 0b001000 | 0b001010 | 0b001100 | 0b001110 => {
+            let inst_idx = (byte1 >> 3 & 0b111) as usize;
+            return decode_regmem_reg(ALU_NAMES[inst_idx], bytes, prefixes);
+        }
+        0b110100 => return decode_shift_regmem(bytes, prefixes),
+        _ => {}
+    };
+
+    // Match 7 bit instructions
+    let opcode = byte1 >> 1;
+    match opcode {
+        0b1010000 => return decode_mov_mem_acc(bytes, true, prefixes),
+        0b1010001 => return decode_mov_mem_acc(bytes, false, prefixes),
+        0b1100011 => return decode_imm_regmem("mov", bytes, prefixes),
+        0b0000010 | 0b0000110 | 0b0001010 | 0b0001110 | 0b0010010 | 0b0010110 | 0b0011010
+        | 0b0011110 => {
+            let inst_idx = (byte1 >> 3 & 0b0000111) as usize;
+            return decode_imm_acc(ALU_NAMES[inst_idx], bytes, prefixes);
+        }
+        0b1000011 => return decode_regmem_reg("xchg", bytes, prefixes),
+        0b1000010 => return decode_regmem_reg("test", bytes, prefixes),
+        0b1010100 => return decode_imm_acc("test", bytes, prefixes),
+        0b1110010 => {
+            let is_out = false;
+            let is_fixed = true;
+            return decode_in_out(is_out, bytes, is_fixed, prefixes);
+        }
+        0b1110110 => {
+            let is_out = false;
+            let is_fixed = false;
+            return decode_in_out(is_out, bytes, is_fixed, prefixes);
+        }
+        0b1110011 => {
+            let is_out = true;
+            let is_fixed = true;
+            return decode_in_out(is_out, bytes, is_fixed, prefixes);
+        }
+        0b1110111 => {
+            let is_out = true;
+            let is_fixed = false;
+            return decode_in_out(is_out, bytes, is_fixed, prefixes);
+        }
+        0b1111011 => {
+            let reg_idx = (bytes[1] >> GRP_INST_IDX_SHIFT & GRP_INST_IDX_MASK) as usize;
+            if reg_idx >= 2 {
+                return decode_unary_regmem(GRP1_NAMES[reg_idx], bytes, prefixes);
+            } else {
+                return decode_imm_regmem("test", bytes, prefixes);
             }
-            0b10001110 | 0b10001100 => {
-                decode_mov_seg(&mut bytes, arg1, &mut segment_override);
-                continue 'decode;
+        }
+        0b1111111 => {
+            let reg_idx = (bytes[1] >> GRP_INST_IDX_SHIFT & GRP_INST_IDX_MASK) as usize;
+            // 0xFE only has inc and dec, 0xFF has nothing at reg 7
+            if reg_idx == 7 || (reg_idx >= 2 && byte1 & W_BIT_MASK == 0) {
+                return decode_invalid_regmem(bytes, prefixes, 0);
             }
-            _ => panic!(
-                "Unsupported instruction. Opcode byte: {byte1:#b}, {:#b}",
-                bytes[0]
-            ),
+            return decode_unary_regmem(GRP2_NAMES[reg_idx], bytes, prefixes);
+        }
+        0b1010010 => return decode_string(["movsb", "movsw", "movsd"], bytes, prefixes),
+        0b1010011 => return decode_string(["cmpsb", "cmpsw", "cmpsd"], bytes, prefixes),
+        0b1010111 => return decode_string(["scasb", "scasw", "scasd"], bytes, prefixes),
+        0b1010110 => return decode_string(["lodsb", "lodsw", "lodsd"], bytes, prefixes),
+        0b1010101 => return decode_string(["stosb", "stosw", "stosd"], bytes, prefixes),
+        0b0110110 if cpu_mode.has_186_instructions() => {
+            return decode_string(["insb", "insw", "insd"], bytes, prefixes);
+        }
+        0b0110111 if cpu_mode.has_186_instructions() => {
+            return decode_string(["outsb", "outsw", "outsd"], bytes, prefixes);
+        }
+        0b1100000 if cpu_mode.has_186_instructions() => {
+            return decode_shift_imm(bytes, prefixes);
+        }
+        _ => (),
+    }
+
+    // The mnemonics that name their operand size pick the dword form behind the prefix
+    let dword = prefixes.operand_size;
+
+    // match 8 bit instruction
+    match byte1 {
+        0b00001111 if cpu_mode == CpuMode::V20 => nec::decode_extended(bytes, prefixes),
+        0b00001111 if cpu_mode == CpuMode::I386 => i386::decode_extended(bytes, prefixes),
+        0b01100000 if cpu_mode.has_186_instructions() => {
+            decode_no_operands(if dword { "pushad" } else { "pusha" }, bytes)
+        }
+        0b01100001 if cpu_mode.has_186_instructions() => {
+            decode_no_operands(if dword { "popad" } else { "popa" }, bytes)
+        }
+        0b01100010 if cpu_mode.has_186_instructions() => decode_load_ptr("bound", bytes, prefixes),
+        0b01101000 | 0b01101010 if cpu_mode.has_186_instructions() => {
+            decode_push_imm(bytes, prefixes)
+        }
+        0b01101001 | 0b01101011 if cpu_mode.has_186_instructions() => {
+            decode_imul_imm(bytes, prefixes)
+        }
+        0b11001000 if cpu_mode.has_186_instructions() => {
+            let size = u16::from_le_bytes([bytes[1], bytes[2]]);
+            let level = bytes[3];
+            *bytes = &bytes[4..];
+            Instruction::new(
+                "enter",
+                vec![Operand::Imm(size as i32), Operand::Imm(level as i32)],
+            )
+        }
+        0b11001001 if cpu_mode.has_186_instructions() => decode_no_operands("leave", bytes),
+        0b01110000..=0b01111111 => decode_jmp_and_loops(bytes, true),
+        0b11100000..=0b11100011 => decode_jmp_and_loops(bytes, false),
+        0b10001111 => decode_unary_regmem("pop", bytes, prefixes),
+        0b00000110 | 0b00001110 | 0b00010110 | 0b00011110 => decode_push_pop_seg("push", bytes),
+        0b00000111 | 0b00001111 | 0b00010111 | 0b00011111 => decode_push_pop_seg("pop", bytes),
+        0b11010111 => decode_no_operands("xlat", bytes),
+        0b10011111 => decode_no_operands("lahf", bytes),
+        0b10011110 => decode_no_operands("sahf", bytes),
+        0b10011100 => decode_no_operands(if dword { "pushfd" } else { "pushf" }, bytes),
+        0b10011101 => decode_no_operands(if dword { "popfd" } else { "popf" }, bytes),
+        0b10001101 => decode_load_ptr("lea", bytes, prefixes),
+        0b11000101 => decode_load_ptr("lds", bytes, prefixes),
+        0b11000100 => decode_load_ptr("les", bytes, prefixes),
+        0b00110111 => decode_no_operands("aaa", bytes),
+        0b00100111 => decode_no_operands("daa", bytes),
+        0b00111111 => decode_no_operands("aas", bytes),
+        0b00101111 => decode_no_operands("das", bytes),
+        0b11010100 => decode_ascii_adjust("aam", bytes),
+        0b11010101 => decode_ascii_adjust("aad", bytes),
+        0b10011000 => decode_no_operands(if dword { "cwde" } else { "cbw" }, bytes),
+        0b10011001 => decode_no_operands(if dword { "cdq" } else { "cwd" }, bytes),
+        0b11000010 => decode_unary_imm("ret", bytes),
+        0b11001010 => decode_unary_imm("retf", bytes),
+        0b11000011 => decode_no_operands("ret", bytes),
+        0b11001011 => decode_no_operands("retf", bytes),
+        0b11001101 => {
+            let interrupt_type = bytes[1];
+            *bytes = &bytes[2..];
+            Instruction::new("int", vec![Operand::Imm(interrupt_type as i32)])
+        }
+        0b11001100 => decode_no_operands("int3", bytes),
+        0b11001110 => decode_no_operands("into", bytes),
+        0b11001111 => decode_no_operands(if dword { "iretd" } else { "iret" }, bytes),
+        0b11111000 => decode_no_operands("clc", bytes),
+        0b11110101 => decode_no_operands("cmc", bytes),
+        0b11111001 => decode_no_operands("stc", bytes),
+        0b11111100 => decode_no_operands("cld", bytes),
+        0b11111101 => decode_no_operands("std", bytes),
+        0b11111010 => decode_no_operands("cli", bytes),
+        0b11111011 => decode_no_operands("sti", bytes),
+        0b11110100 => decode_no_operands("hlt", bytes),
+        0b10011011 => decode_no_operands("wait", bytes),
+        0b10011010 => decode_direct_intersegment("call", bytes, prefixes),
+        0b11101010 => decode_direct_intersegment("jmp", bytes, prefixes),
+        0b11101001 => decode_jmp_and_call_long_offset("jmp", bytes, prefixes),
+        0b11101000 => decode_jmp_and_call_long_offset("call", bytes, prefixes),
+        0b11101011 => decode_jmp_short(bytes),
+        0b10001110 | 0b10001100 => decode_mov_seg(bytes, cpu_mode, prefixes),
+        0b11011000..=0b11011111 => {
+            let encoding = *bytes;
+            *bytes = &bytes[1..];
+            decode_effective_address_calculation(bytes, prefixes);
+            Instruction::coprocessor(&encoding[..encoding.len() - bytes.len()])
+        }
+        // Opcodes the CPU does not define are kept as a byte, decoding goes on after it
+        _ => {
+            *bytes = &bytes[1..];
+            Instruction::invalid(&[byte1])
         }
     }
 }
 
+// An undefined ModR/M form, kept as the bytes of the opcode, the ModR/M byte, the displacement
+// and immediate_size bytes of immediate
+fn decode_invalid_regmem(
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+    immediate_size: usize,
+) -> Instruction {
+    let encoding = *bytes;
+    *bytes = &bytes[1..];
+    decode_effective_address_calculation(bytes, prefixes);
+    *bytes = &bytes[immediate_size..];
+    Instruction::invalid(&encoding[..encoding.len() - bytes.len()])
+}
+
 enum EffectiveAddress {
-    Reg(&'static str),           // mod=0b11
-    Direct(u16),                 // rm=0b110 mod=0
-    Indirect(&'static str, i16), // disp could be zero
+    Reg(usize),           // mod=0b11
+    Direct(u16),          // rm=0b110 mod=0
+    // Index into EFFECTIVE_ADDRESS_BASES, disp if one is encoded and whether it is a word
+    Indirect(usize, Option<i16>, bool),
+    // 32-bit addressing behind the address-size prefix, register indices into REGISTER_MAP
+    Indirect32 {
+        base: Option<usize>,
+        index: Option<usize>,
+        scale: u8,
+        disp: i32,
+    },
 }
 
-// mod_bytes, rm, reg, displacement
+// reg, effective address
 fn decode_effective_address_calculation(
     bytes: &mut &[u8],
-    w_bit: usize,
+    prefixes: &Prefixes,
 ) -> (usize, EffectiveAddress) {
     const MOD_SHIFT: u8 = 6;
     const RM_MASK: u8 = 0b000000111;
@@ -513,7 +1579,9 @@ fn decode_effective_address_calculation(
     let reg = ((byte >> REG_SHIFT) & REG_MASK) as usize;
 
     if mod_bytes == 0b11 {
-        (reg, EffectiveAddress::Reg(REGISTER_MAP[r_m][w_bit]))
+        (reg, EffectiveAddress::Reg(r_m))
+    } else if prefixes.address_size {
+        (reg, i386::decode_address32(bytes, mod_bytes, r_m))
     } else {
         // Direct address mode
         if r_m == 0b110 && mod_bytes == 0 {
@@ -522,127 +1590,95 @@ fn decode_effective_address_calculation(
 
             (reg, EffectiveAddress::Direct(address))
         } else {
-            let rm_reg_str = match r_m {
-                0b000 => "bx + si",
-                0b001 => "bx + di",
-                0b010 => "bp + si",
-                0b011 => "bp + di",
-                0b100 => "si",
-                0b101 => "di",
-                0b110 => "bp",
-                0b111 => "bx",
-                _ => panic!("Invalid R/M"),
-            };
-            let mut displacement: i16 = 0;
+            let mut displacement = None;
+            let wide = mod_bytes == 0b10;
             if mod_bytes == 0b01 {
-                displacement = (bytes[0] as i8) as i16; // sign extend to 16 bits
+                displacement = Some((bytes[0] as i8) as i16); // sign extend to 16 bits
                 *bytes = &bytes[1..];
             } else if mod_bytes == 0b10 {
-                displacement = i16::from_le_bytes([bytes[0], bytes[1]]);
+                displacement = Some(i16::from_le_bytes([bytes[0], bytes[1]]));
                 *bytes = &bytes[2..];
             }
-            (reg, EffectiveAddress::Indirect(rm_reg_str, displacement))
+            (reg, EffectiveAddress::Indirect(r_m, displacement, wide))
         }
     }
 }
 
-fn write_effective_address_size(buffer: &mut String, eff_add: &EffectiveAddress, w_bit: usize) {
-    if !matches!(*eff_add, EffectiveAddress::Reg(_)) {
-        if w_bit == 1 {
-            buffer.push_str("word ");
-        } else {
-            buffer.push_str("byte ");
-        }
+// Registers come out of the r/m field for mod=0b11, everything else is a memory operand
+fn regmem_operand(eff_add: EffectiveAddress, width: usize, sized: bool) -> Operand {
+    match eff_add {
+        EffectiveAddress::Reg(reg_idx) => Operand::Reg(reg_idx, width),
+        address => Operand::Mem {
+            address,
+            width,
+            sized,
+        },
     }
 }
 
-fn write_effective_address(
-    buffer: &mut String,
-    eff_add: &EffectiveAddress,
-    segment_override: &mut SegOverrideReg,
-) {
-    if !matches!(*eff_add, EffectiveAddress::Reg(_))
-        && let SegOverrideReg(Some(reg)) = segment_override
-    {
-        write!(buffer, "{reg}").unwrap();
-        *segment_override = SegOverrideReg(None);
-    }
-    match *eff_add {
-        EffectiveAddress::Reg(rm_reg_str) => {
-            buffer.push_str(rm_reg_str);
+// Immediates of every width are sign extended
+fn read_immediate(bytes: &mut &[u8], width: usize) -> i32 {
+    match width {
+        0 => {
+            let immediate = bytes[0] as i8 as i32;
+            *bytes = &bytes[1..];
+            immediate
         }
-        EffectiveAddress::Direct(address) => {
-            write!(buffer, "[{address}]").unwrap();
+        1 => {
+            let immediate = i16::from_le_bytes([bytes[0], bytes[1]]) as i32;
+            *bytes = &bytes[2..];
+            immediate
         }
-        EffectiveAddress::Indirect(base, disp) => {
-            if disp > 0 {
-                write!(buffer, "[{} + {}]", base, disp).unwrap();
-            } else if disp < 0 {
-                write!(buffer, "[{} - {}]", base, disp.unsigned_abs()).unwrap();
-            } else {
-                write!(buffer, "[{}]", base).unwrap();
-            }
+        _ => {
+            let immediate = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            *bytes = &bytes[4..];
+            immediate
         }
     }
 }
 
 fn decode_imm_regmem(
-    inst_name: &str,
+    inst_name: &'static str,
     bytes: &mut &[u8],
-    dst: &mut String,
-    src: &mut String,
-    segment_override: &mut SegOverrideReg,
-) {
+    prefixes: &Prefixes,
+) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
-    let w_bit = (byte1 & W_BIT_MASK) as usize;
+    let width = prefixes.width((byte1 & W_BIT_MASK) as usize);
 
-    let (_, eff_add) = decode_effective_address_calculation(bytes, w_bit);
-    write_effective_address_size(dst, &eff_add, w_bit);
-    write_effective_address(dst, &eff_add, segment_override);
+    let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    let dst = regmem_operand(eff_add, width, true);
+    let immediate = read_immediate(bytes, width);
 
-    if w_bit == 1 {
-        let immediate = i16::from_le_bytes([bytes[0], bytes[1]]);
-        *bytes = &bytes[2..];
-        write!(src, "{immediate}").unwrap();
-    } else {
-        let immediate = (bytes[0] as i8) as i16; // sign extend to 16 bits
-        *bytes = &bytes[1..];
-        write!(src, "{immediate}").unwrap();
-    }
-
-    println!("{inst_name} {dst}, {src}");
+    Instruction::new(inst_name, vec![dst, Operand::Imm(immediate)])
 }
 
 fn decode_regmem_reg(
-    instruction: &str,
+    instruction: &'static str,
     bytes: &mut &[u8],
-    arg1: &mut String,
-    arg2: &mut String,
-    segment_override: &mut SegOverrideReg,
-) {
+    prefixes: &Prefixes,
+) -> Instruction {
     const D_BIT_SHIFT: u8 = 1;
     const D_BIT_MASK: u8 = 0b00000010;
 
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
-    let w_bit = (byte1 & W_BIT_MASK) as usize;
+    let width = prefixes.width((byte1 & W_BIT_MASK) as usize);
     let d_bit: bool = matches!((byte1 & D_BIT_MASK) >> D_BIT_SHIFT, 1);
 
-    let (dst, src) = match d_bit {
-        true => (&mut *arg1, &mut *arg2),
-        false => (&mut *arg2, &mut *arg1),
-    };
-
-    let (reg, eff_add) = decode_effective_address_calculation(bytes, w_bit);
-    dst.push_str(REGISTER_MAP[reg][w_bit]);
-    write_effective_address(src, &eff_add, segment_override);
+    let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    let reg = Operand::Reg(reg, width);
+    let regmem = regmem_operand(eff_add, width, false);
 
-    println!("{instruction} {arg1}, {arg2}");
+    let operands = match d_bit {
+        true => vec![reg, regmem],
+        false => vec![regmem, reg],
+    };
+    Instruction::new(instruction, operands)
 }
 
-fn decode_mov_imm_reg(bytes: &mut &[u8]) {
+fn decode_mov_imm_reg(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     // This is the only instruction with the w bit not at the end of the opcode byte
     const W_BIT_MASK: u8 = 0b00001000;
     const W_BIT_SHIFT: u8 = 3;
@@ -650,88 +1686,85 @@ fn decode_mov_imm_reg(bytes: &mut &[u8]) {
 
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
-    let w_bit = ((byte1 & W_BIT_MASK) >> W_BIT_SHIFT) as usize;
+    let width = prefixes.width(((byte1 & W_BIT_MASK) >> W_BIT_SHIFT) as usize);
     let reg = (byte1 & REG_MASK) as usize;
-    let reg_str: &str = REGISTER_MAP[reg][w_bit];
 
-    let mut immediate: i16 = 0;
-    if w_bit == 1 {
-        immediate = i16::from_le_bytes([bytes[0], bytes[1]]);
-        *bytes = &bytes[2..];
-    } else {
-        immediate |= (bytes[0] as i8) as i16; // sign extend to 16 bits
-        *bytes = &bytes[1..];
-    }
+    let immediate = read_immediate(bytes, width);
 
-    println!("mov {}, {}", reg_str, immediate);
+    Instruction::new(
+        "mov",
+        vec![Operand::Reg(reg, width), Operand::Imm(immediate)],
+    )
 }
 
-fn decode_mov_mem_acc(bytes: &mut &[u8], acc_first: bool) {
-    let address = u16::from_le_bytes([bytes[1], bytes[2]]);
-    *bytes = &bytes[3..];
+fn decode_mov_mem_acc(bytes: &mut &[u8], acc_first: bool, prefixes: &Prefixes) -> Instruction {
+    let width = prefixes.width((bytes[0] & W_BIT_MASK) as usize);
+    *bytes = &bytes[1..];
 
+    let address = if prefixes.address_size {
+        let disp = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        *bytes = &bytes[4..];
+        EffectiveAddress::Indirect32 {
+            base: None,
+            index: None,
+            scale: 1,
+            disp,
+        }
+    } else {
+        let address = u16::from_le_bytes([bytes[0], bytes[1]]);
+        *bytes = &bytes[2..];
+        EffectiveAddress::Direct(address)
+    };
+
+    let acc = Operand::Reg(0, width);
+    let mem = Operand::Mem {
+        address,
+        width,
+        sized: false,
+    };
     if acc_first {
-        println!("mov ax, [{address}]");
+        Instruction::new("mov", vec![acc, mem])
     } else {
-        println!("mov [{address}], ax");
+        Instruction::new("mov", vec![mem, acc])
     }
 }
 
-fn decode_alu_imm_regmem(
-    bytes: &mut &[u8],
-    dst: &mut String,
-    src: &mut String,
-    segment_override: &mut SegOverrideReg,
-) {
+fn decode_alu_imm_regmem(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
-    let w_bit = (byte1 & W_BIT_MASK) as usize;
+    let width = prefixes.width((byte1 & W_BIT_MASK) as usize);
     let s_bit = (byte1 >> S_BIT_SHIFT) & S_BIT_MASK;
 
-    let (inst_idx, eff_add) = decode_effective_address_calculation(bytes, w_bit);
+    let (inst_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
 
     let inst_name = ALU_NAMES[inst_idx];
-    write_effective_address_size(dst, &eff_add, w_bit);
-    write_effective_address(dst, &eff_add, segment_override);
+    let dst = regmem_operand(eff_add, width, true);
 
-    if w_bit == 0 {
-        let immediate = bytes[0] as i8;
-        write!(src, "{immediate}").unwrap();
-        *bytes = &bytes[1..];
-    } else if s_bit == 0 && w_bit == 1 {
-        let immediate = i16::from_le_bytes([bytes[0], bytes[1]]);
-        write!(src, "{immediate}").unwrap();
-        *bytes = &bytes[2..];
+    // With the s bit set a single byte is sign extended to the operand width
+    let immediate = if s_bit == 1 {
+        read_immediate(bytes, 0)
     } else {
-        let immediate = bytes[0] as i8 as i16; // sign extend to 16 bits
-        write!(src, "{immediate}").unwrap();
-        *bytes = &bytes[1..];
-    }
+        read_immediate(bytes, width)
+    };
 
-    println!("{inst_name} {dst}, {src}");
+    Instruction::new(inst_name, vec![dst, Operand::Imm(immediate)])
 }
 
-fn decode_imm_acc(inst_name: &str, bytes: &mut &[u8]) {
+fn decode_imm_acc(inst_name: &'static str, bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
-    let w_bit = (byte1 & W_BIT_MASK) as usize;
-
-    let mut immediate: i16 = 0;
-    if w_bit == 1 {
-        immediate = i16::from_le_bytes([bytes[0], bytes[1]]);
-        *bytes = &bytes[2..];
-    } else {
-        immediate |= (bytes[0] as i8) as i16; // sign extend to 16 bits
-        *bytes = &bytes[1..];
-    }
-    let acc_name = if w_bit == 1 { "ax" } else { "al" };
+    let width = prefixes.width((byte1 & W_BIT_MASK) as usize);
+    let immediate = read_immediate(bytes, width);
 
-    println!("{inst_name} {acc_name}, {immediate}");
+    Instruction::new(
+        inst_name,
+        vec![Operand::Reg(0, width), Operand::Imm(immediate)],
+    )
 }
 
-fn decode_jmp_and_loops(bytes: &mut &[u8], is_jmp: bool) {
+fn decode_jmp_and_loops(bytes: &mut &[u8], is_jmp: bool) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
@@ -741,164 +1774,459 @@ fn decode_jmp_and_loops(bytes: &mut &[u8], is_jmp: bool) {
         LOOP_NAMES[(byte1 & 0b11) as usize]
     };
 
-    let disp = bytes[0] as i8;
-    *bytes = &bytes[1..];
+    let disp = read_immediate(bytes, 0);
 
-    println!("{inst_name} $+2+{disp}");
+    Instruction::new(inst_name, vec![Operand::Rel(disp, 0)])
 }
 
-fn decode_one_byte_reg(inst_name: &str, bytes: &mut &[u8]) {
+fn decode_one_byte_reg(
+    inst_name: &'static str,
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
     let reg_idx = (byte1 & 0b111) as usize;
 
-    println!("{inst_name} {}", REGISTER_MAP[reg_idx][1]);
+    Instruction::new(inst_name, vec![Operand::Reg(reg_idx, prefixes.width(1))])
 }
 
-fn decode_push_pop_seg(inst_name: &str, bytes: &mut &[u8]) {
+fn decode_push_pop_seg(inst_name: &'static str, bytes: &mut &[u8]) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
     let seg_idx = ((byte1 >> 3) & 0b11) as usize;
 
-    println!("{inst_name} {}", SEGMENT_REGS[seg_idx]);
+    Instruction::new(inst_name, vec![Operand::SegReg(seg_idx)])
 }
 
-fn decode_mov_seg(bytes: &mut &[u8], dst: &mut String, segment_override: &mut SegOverrideReg) {
+fn decode_mov_seg(bytes: &mut &[u8], cpu_mode: CpuMode, prefixes: &Prefixes) -> Instruction {
     const D_BIT_SHIFT: u8 = 1;
+    let encoding = *bytes;
     let d_bit = bytes[0] >> D_BIT_SHIFT & 1;
     *bytes = &bytes[1..];
 
-    // In this case the w_bit does not matter, 1 is passed for convenience
-    let (reg_idx, eff_add) = decode_effective_address_calculation(bytes, 1);
-    write_effective_address(dst, &eff_add, segment_override);
+    let (reg_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    // The 386 only has segment registers up to gs
+    if cpu_mode == CpuMode::I386 && reg_idx >= SEGMENT_REGS.len() {
+        return Instruction::invalid(&encoding[..encoding.len() - bytes.len()]);
+    }
+    // Segment registers are always a word wide
+    let regmem = regmem_operand(eff_add, 1, false);
 
-    // There are only 4 Segment registers so only the last 2 bits are needed
-    let reg_name = SEGMENT_REGS[reg_idx & 0b11];
+    // The 8086 has only 4 Segment registers and ignores the high bit of reg, the 386 adds fs and gs
+    let seg_idx = if cpu_mode == CpuMode::I386 {
+        reg_idx
+    } else {
+        reg_idx & 0b11
+    };
+    let seg_reg = Operand::SegReg(seg_idx);
 
     if d_bit == 1 {
-        println!("mov {reg_name}, {dst}");
+        Instruction::new("mov", vec![seg_reg, regmem])
     } else {
-        println!("mov {dst}, {reg_name}");
+        Instruction::new("mov", vec![regmem, seg_reg])
     }
 }
 
-fn decode_xchg_acc(bytes: &mut &[u8]) {
+fn decode_xchg_acc(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
     let reg_idx = (byte1 & 0b111) as usize;
-    println!("xchg ax, {}", REGISTER_MAP[reg_idx][1]);
+    let width = prefixes.width(1);
+    Instruction::new(
+        "xchg",
+        vec![Operand::Reg(0, width), Operand::Reg(reg_idx, width)],
+    )
 }
 
 fn decode_in_out(
     is_out: bool,
     bytes: &mut &[u8],
-    arg1: &mut String,
-    arg2: &mut String,
     is_fixed: bool,
-) {
+    prefixes: &Prefixes,
+) -> Instruction {
     let byte1 = bytes[0];
     *bytes = &bytes[1..];
 
-    let w_bit = (byte1 & W_BIT_MASK) as usize;
-    let a_reg = REGISTER_MAP[0][w_bit];
+    let width = prefixes.width((byte1 & W_BIT_MASK) as usize);
+    let a_reg = Operand::Reg(0, width);
 
-    let inst_name = if is_out { "out" } else { "in" };
-    let (dst, src) = match is_out {
-        true => (&mut *arg2, &mut *arg1),
-        false => (&mut *arg1, &mut *arg2),
-    };
-    write!(dst, "{a_reg}").unwrap();
-
-    if is_fixed {
+    let port = if is_fixed {
         let port_num = bytes[0];
         *bytes = &bytes[1..];
-        write!(src, "{port_num}").unwrap();
+        Operand::Imm(port_num as i32)
     } else {
-        write!(src, "dx").unwrap();
+        Operand::Reg(2, 1) // dx
+    };
+
+    match is_out {
+        true => Instruction::new("out", vec![port, a_reg]),
+        false => Instruction::new("in", vec![a_reg, port]),
     }
-    println!("{inst_name} {arg1}, {arg2}");
 }
 
-fn decode_load_ptr(
-    inst_name: &str,
-    bytes: &mut &[u8],
-    dst: &mut String,
-    segment_override: &mut SegOverrideReg,
-) {
+fn decode_load_ptr(inst_name: &'static str, bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     *bytes = &bytes[1..];
 
-    let (reg, eff_add) = decode_effective_address_calculation(bytes, 1);
-    let reg_str = REGISTER_MAP[reg][1];
-    write_effective_address(dst, &eff_add, segment_override);
+    let width = prefixes.width(1);
+    let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    let src = regmem_operand(eff_add, width, false);
 
-    println!("{inst_name} {reg_str}, {dst}");
+    Instruction::new(inst_name, vec![Operand::Reg(reg, width), src])
 }
 
 fn decode_unary_regmem(
-    inst_name: &str,
+    inst_name: &'static str,
     bytes: &mut &[u8],
-    dst: &mut String,
-    segment_override: &mut SegOverrideReg,
-) {
+    prefixes: &Prefixes,
+) -> Instruction {
     const INTERSEGMENT_SHIFT: u8 = 3;
-    let w_bit = (bytes[0] & W_BIT_MASK) as usize;
+    let width = prefixes.width((bytes[0] & W_BIT_MASK) as usize);
     *bytes = &bytes[1..];
 
     let intersegment_bit = bytes[0] >> INTERSEGMENT_SHIFT & 1;
-    if intersegment_bit == 1 && (inst_name == "call" || inst_name == "jmp") {
-        dst.push_str("far ");
-    }
-
-    let (_, eff_add) = decode_effective_address_calculation(bytes, w_bit);
+    let far = intersegment_bit == 1 && (inst_name == "call" || inst_name == "jmp");
 
-    write_effective_address_size(dst, &eff_add, w_bit);
-    write_effective_address(dst, &eff_add, segment_override);
+    let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
 
-    println!("{inst_name} {dst}");
+    let mut instruction = Instruction::new(inst_name, vec![regmem_operand(eff_add, width, true)]);
+    instruction.far = far;
+    instruction
 }
 
-fn decode_shift_regmem(bytes: &mut &[u8], dst: &mut String, segment_override: &mut SegOverrideReg) {
+fn decode_shift_regmem(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
     const V_BIT_SHIFT: u8 = 1;
     const V_BIT_MASK: u8 = 1;
 
-    let w_bit = (bytes[0] & W_BIT_MASK) as usize;
+    let encoding = *bytes;
+    let width = prefixes.width((bytes[0] & W_BIT_MASK) as usize);
     let v_bit = (bytes[0] >> V_BIT_SHIFT) & V_BIT_MASK;
     *bytes = &bytes[1..];
 
     let inst_idx = ((bytes[0] >> GRP_INST_IDX_SHIFT) & GRP_INST_IDX_MASK) as usize;
     let inst_name = SHIFT_NAMES[inst_idx];
 
-    let (_, eff_add) = decode_effective_address_calculation(bytes, w_bit);
+    let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    if inst_name == "???" {
+        return Instruction::invalid(&encoding[..encoding.len() - bytes.len()]);
+    }
+
+    let dst = regmem_operand(eff_add, width, true);
+    let src = if v_bit == 1 {
+        Operand::Reg(1, 0) // cl
+    } else {
+        Operand::Imm(1)
+    };
+
+    Instruction::new(inst_name, vec![dst, src])
+}
+
+// The 186 shifts by an immediate count
+fn decode_shift_imm(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
+    if SHIFT_NAMES[(bytes[1] >> GRP_INST_IDX_SHIFT & GRP_INST_IDX_MASK) as usize] == "???" {
+        return decode_invalid_regmem(bytes, prefixes, 1);
+    }
+    let width = prefixes.width((bytes[0] & W_BIT_MASK) as usize);
+    *bytes = &bytes[1..];
+
+    let (inst_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    let dst = regmem_operand(eff_add, width, true);
+    let count = bytes[0];
+    *bytes = &bytes[1..];
+
+    Instruction::new(SHIFT_NAMES[inst_idx], vec![dst, Operand::Imm(count as i32)])
+}
+
+// 0x6A pushes a sign extended byte, 0x68 a word or dword
+fn decode_push_imm(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
+    let byte1 = bytes[0];
+    *bytes = &bytes[1..];
+    let s_bit = (byte1 >> S_BIT_SHIFT) & S_BIT_MASK == 1;
+    immediate_instruction("push", vec![], s_bit, bytes, prefixes)
+}
 
-    write_effective_address_size(dst, &eff_add, w_bit);
-    write_effective_address(dst, &eff_add, segment_override);
-    let src = if v_bit == 1 { "cl" } else { "1" };
+// imul reg, r/m, imm with a sign extended byte for 0x6B and a word or dword for 0x69
+fn decode_imul_imm(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
+    let byte1 = bytes[0];
+    *bytes = &bytes[1..];
+    let width = prefixes.width(1);
 
-    println!("{inst_name} {dst}, {src}");
+    let (reg, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+    let operands = vec![Operand::Reg(reg, width), regmem_operand(eff_add, width, false)];
+    let s_bit = (byte1 >> S_BIT_SHIFT) & S_BIT_MASK == 1;
+    immediate_instruction("imul", operands, s_bit, bytes, prefixes)
 }
 
-fn decode_unary_imm(inst_name: &str, bytes: &mut &[u8]) {
+// Appends the immediate of push and imul, marking a full-sized one NASM would shorten
+fn immediate_instruction(
+    inst_name: &'static str,
+    mut operands: Vec<Operand>,
+    s_bit: bool,
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+) -> Instruction {
+    let immediate = if s_bit {
+        read_immediate(bytes, 0)
+    } else {
+        read_immediate(bytes, prefixes.width(1))
+    };
+    operands.push(Operand::Imm(immediate));
+    let mut instruction = Instruction::new(inst_name, operands);
+    instruction.wide_immediate = !s_bit && i8::try_from(immediate).is_ok();
+    instruction
+}
+
+fn decode_unary_imm(inst_name: &'static str, bytes: &mut &[u8]) -> Instruction {
     *bytes = &bytes[1..];
     let immediate = i16::from_le_bytes([bytes[0], bytes[1]]);
     *bytes = &bytes[2..];
 
-    println!("{inst_name} {immediate}")
+    Instruction::new(inst_name, vec![Operand::Imm(immediate as i32)])
+}
+
+fn decode_no_operands(inst_name: &'static str, bytes: &mut &[u8]) -> Instruction {
+    *bytes = &bytes[1..];
+    Instruction::new(inst_name, vec![])
+}
+
+fn decode_string(
+    inst_names: [&'static str; 3],
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+) -> Instruction {
+    let width = prefixes.width((bytes[0] & W_BIT_MASK) as usize);
+    *bytes = &bytes[1..];
+    Instruction::new(inst_names[width], vec![])
+}
+
+fn decode_ascii_adjust(inst_name: &'static str, bytes: &mut &[u8]) -> Instruction {
+    const DEFAULT_BASE: u8 = 10;
+    let base = bytes[1];
+    *bytes = &bytes[2..];
+
+    // The base is implied by the bare mnemonic unless the program uses an unusual one
+    if base == DEFAULT_BASE {
+        Instruction::new(inst_name, vec![])
+    } else {
+        Instruction::new(inst_name, vec![Operand::Imm(base as i32)])
+    }
+}
+
+fn decode_direct_intersegment(
+    inst_name: &'static str,
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+) -> Instruction {
+    *bytes = &bytes[1..];
+    let offset = if prefixes.operand_size {
+        let offset = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        *bytes = &bytes[4..];
+        offset
+    } else {
+        let offset = u16::from_le_bytes([bytes[0], bytes[1]]);
+        *bytes = &bytes[2..];
+        offset as u32
+    };
+    let segment = u16::from_le_bytes([bytes[0], bytes[1]]);
+    *bytes = &bytes[2..];
+
+    Instruction::new(inst_name, vec![Operand::Far(segment, offset)])
 }
 
-fn decode_direct_intersegment(inst_name: &str, bytes: &mut &[u8]) {
-    let offset = u16::from_le_bytes([bytes[1], bytes[2]]);
-    let segment = u16::from_le_bytes([bytes[3], bytes[4]]);
-    *bytes = &bytes[5..];
+fn decode_jmp_and_call_long_offset(
+    inst_name: &'static str,
+    bytes: &mut &[u8],
+    prefixes: &Prefixes,
+) -> Instruction {
+    *bytes = &bytes[1..];
+    let width = prefixes.width(1);
+    let offset = read_immediate(bytes, width);
 
-    println!("{inst_name} {segment}:{offset}");
+    Instruction::new(inst_name, vec![Operand::Rel(offset, width)])
 }
 
-fn decode_jmp_and_call_long_offset(inst_name: &str, bytes: &mut &[u8]) {
-    let offset = i16::from_le_bytes([bytes[1], bytes[2]]);
-    *bytes = &bytes[3..];
+fn decode_jmp_short(bytes: &mut &[u8]) -> Instruction {
+    *bytes = &bytes[1..];
+    let disp = read_immediate(bytes, 0);
+
+    Instruction::new("jmp", vec![Operand::Rel(disp, 0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The listing lines of bytes decoded one instruction after the other, jumps relative to $.
+    // The loader tests list the code they find with it.
+    pub(crate) fn listing(bytes: &[u8], cpu_mode: CpuMode, syntax: Syntax) -> Vec<String> {
+        let formatter = Formatter::new(syntax, None);
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let instruction = decode_before(&bytes[offset..], cpu_mode);
+            let mut line = String::new();
+            formatter.format(&instruction, offset, &mut line);
+            // Stray prefixes come on lines of their own
+            lines.extend(line.lines().map(String::from));
+            offset += instruction.size;
+        }
+        lines
+    }
+
+    // Options from the arguments after the program name
+    fn options(args: &str) -> Options {
+        let args: Vec<String> = ["sim8086"]
+            .into_iter()
+            .chain(args.split(' '))
+            .map(String::from)
+            .collect();
+        parse_args(&args)
+    }
 
-    println!("{inst_name} $+3+{offset}"); // 3 is the size of the instruction
+    #[test]
+    fn v20_extensions_in_both_syntaxes() {
+        // rol4 bl / add4s / set1 byte [4096], cl / pusha / mov ax, bx
+        let bytes = [
+            0x0F, 0x28, 0xC3, 0x0F, 0x20, 0x0F, 0x14, 0x06, 0x00, 0x10, 0x60, 0x89, 0xD8,
+        ];
+        assert_eq!(
+            listing(&bytes, CpuMode::V20, Syntax::Intel),
+            ["rol4 bl", "add4s", "set1 byte [4096], cl", "pusha", "mov ax, bx"]
+        );
+        assert_eq!(
+            listing(&bytes, CpuMode::V20, Syntax::Nec),
+            ["rol4 bl", "add4s", "set1 byte [4096], cl", "push r", "mov aw, bw"]
+        );
+        // An unknown 0F opcode stays bytes, the 8086 decodes 0F as pop cs
+        assert_eq!(
+            listing(&[0x0F, 0x50], CpuMode::V20, Syntax::Intel),
+            ["db 0x0f, 0x50 ; invalid encoding"]
+        );
+        assert_eq!(listing(&[0x0F], CpuMode::I8086, Syntax::Intel), ["pop cs"]);
+    }
+
+    #[test]
+    fn i386_operand_and_address_sizes() {
+        // mov eax, 0x12345678 / mov ax, [ebx + eax*4] / imul ax, bx / je near / mov ax, fs:[bx]
+        let bytes = [
+            0x66, 0xB8, 0x78, 0x56, 0x34, 0x12, 0x67, 0x8B, 0x04, 0x83, 0x0F, 0xAF, 0xC3, 0x0F,
+            0x84, 0x10, 0x00, 0x64, 0x8B, 0x07, 0x66, 0x60,
+        ];
+        assert_eq!(
+            listing(&bytes, CpuMode::I386, Syntax::Intel),
+            [
+                "mov eax, 305419896",
+                "mov ax, [ebx + eax*4]",
+                "imul ax, bx",
+                "je near $+4+16",
+                "mov ax, fs:[bx]",
+                "pushad",
+            ]
+        );
+        // ud2 is not decoded, and the 8086 only looks at two bits of the segment register
+        assert_eq!(
+            listing(&[0x0F, 0x0B], CpuMode::I386, Syntax::Intel),
+            ["db 0x0f, 0x0b ; invalid encoding"]
+        );
+        assert_eq!(listing(&[0x8E, 0xE0], CpuMode::I386, Syntax::Intel), ["mov fs, ax"]);
+        assert_eq!(listing(&[0x8E, 0xE0], CpuMode::I8086, Syntax::Intel), ["mov es, ax"]);
+    }
+
+    #[test]
+    fn stray_prefixes_are_written_as_bytes() {
+        let bytes = [
+            0x66, 0x89, 0xD8, 0xF0, 0xF0, 0x90, 0x26, 0x3E, 0x8B, 0x07, 0x2E, 0x2E, 0x90,
+        ];
+        assert_eq!(
+            listing(&bytes, CpuMode::I8086, Syntax::Intel),
+            [
+                "db 0x66 ; invalid encoding",
+                "mov ax, bx",
+                "db 0xf0 ; prefix repeated or out of order",
+                "db 0xf0 ; lock on an instruction that cannot be locked",
+                "xchg ax, ax",
+                "db 0x26 ; prefix repeated or out of order",
+                "mov ax, ds:[bx]",
+                "db 0x2e ; prefix repeated or out of order",
+                "cs xchg ax, ax",
+            ]
+        );
+    }
+
+    #[test]
+    fn com_programs_start_at_0x100_behind_the_psp() {
+        // mov cl, [0x80] / jmp short to the mov
+        let bytes = [0x8A, 0x0E, 0x80, 0x00, 0xEB, 0xFA];
+        let formatter = Formatter::new(Syntax::Intel, Some(com::ORIGIN));
+        let mut line = String::new();
+        let jmp = decode_before(&bytes[4..], CpuMode::I8086);
+        formatter.format(&jmp, 4, &mut line);
+        assert_eq!(line, "jmp short 0x100");
+        let mov = decode_before(&bytes, CpuMode::I8086);
+        assert_eq!(
+            mov.operands.iter().find_map(psp_reference),
+            Some((0x80, "command tail length"))
+        );
+        assert_eq!(com::psp_field(0x82), Some("command tail"));
+        assert_eq!(com::psp_field(0x100), None);
+    }
+
+    #[test]
+    fn byte_ranges_and_origins() {
+        let ranged = options("--start 0x10 --length 4 --origin 1000:0100h --hex 90");
+        assert_eq!(byte_range(&ranged, 0x20), 0x10..0x14);
+        let origin = ranged.origin.unwrap();
+        assert_eq!((origin.segment, origin.offset), (Some(0x1000), 0x100));
+        assert_eq!(byte_range(&options("--end 8 --hex 90"), 0x20), 0..8);
+        assert_eq!(parse_number("7C00h"), Some(0x7C00));
+        assert_eq!(parse_number("0x7c00"), Some(0x7C00));
+        assert_eq!(parse_number("31744"), Some(0x7C00));
+        assert_eq!(nasm_hex(0xC000), "0C000h");
+
+        // With an origin jump targets are absolute: a loop back to the start at 100h
+        let formatter = Formatter::new(Syntax::Intel, Some(origin.offset));
+        let mut line = String::new();
+        let instruction = decode_before(&[0xE2, 0xFC], CpuMode::I8086);
+        formatter.format(&instruction, 2, &mut line);
+        assert_eq!(line, "loop 0x100");
+    }
+
+    #[test]
+    fn hex_strings_and_stdin_as_input() {
+        assert_eq!(
+            parse_hex_bytes("89 d9,0x88e5"),
+            Some(vec![0x89, 0xD9, 0x88, 0xE5])
+        );
+        assert_eq!(parse_hex_bytes("89d"), None);
+        assert_eq!(parse_hex_bytes(" "), None);
+        let Input::Bytes(bytes) = options("--hex 89d988e5").input else {
+            panic!("--hex gives the bytes");
+        };
+        assert_eq!(
+            listing(&bytes, CpuMode::I8086, Syntax::Intel),
+            ["mov cx, bx", "mov ch, ah"]
+        );
+        assert!(matches!(options("-").input, Input::Stdin));
+    }
+
+    #[test]
+    fn displacements_keep_their_size() {
+        let bytes = [
+            0x8B, 0x45, 0x00, 0x8B, 0x85, 0x05, 0x00, 0x8B, 0x46, 0x00, 0x8B, 0x86, 0x00, 0x00,
+            0x8B, 0x85, 0x00, 0x01,
+        ];
+        assert_eq!(
+            listing(&bytes, CpuMode::I8086, Syntax::Intel),
+            [
+                "mov ax, [byte di + 0]",
+                "mov ax, [word di + 5]",
+                "mov ax, [bp]",
+                "mov ax, [word bp + 0]",
+                "mov ax, [di + 256]",
+            ]
+        );
+    }
 }
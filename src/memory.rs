@@ -0,0 +1,71 @@
+// The 1 MiB address space of the simulator. Addresses are a segment and a 16-bit offset, the
+// physical address is segment * 16 + offset and wraps around at 1 MiB as on an 8086.
+
+const SIZE: usize = 1 << 20;
+const FETCH_SIZE: usize = 16;
+
+pub struct Memory {
+    bytes: Vec<u8>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            bytes: vec![0; SIZE],
+        }
+    }
+
+    pub fn physical(segment: u16, offset: u16) -> usize {
+        ((segment as usize) << 4).wrapping_add(offset as usize) % SIZE
+    }
+
+    pub fn read_u8(&self, segment: u16, offset: u16) -> u8 {
+        self.bytes[Self::physical(segment, offset)]
+    }
+
+    // The offset of the high byte wraps around within the segment, so a word at offset 0xFFFF
+    // takes its high byte from offset 0
+    pub fn read_u16(&self, segment: u16, offset: u16) -> u16 {
+        u16::from_le_bytes([
+            self.read_u8(segment, offset),
+            self.read_u8(segment, offset.wrapping_add(1)),
+        ])
+    }
+
+    pub fn write_u8(&mut self, segment: u16, offset: u16, value: u8) {
+        self.bytes[Self::physical(segment, offset)] = value;
+    }
+
+    pub fn write_u16(&mut self, segment: u16, offset: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_u8(segment, offset, low);
+        self.write_u8(segment, offset.wrapping_add(1), high);
+    }
+
+    // Enough bytes at segment:offset for the longest instruction the decoder accepts
+    pub fn fetch(&self, segment: u16, offset: u16) -> [u8; FETCH_SIZE] {
+        std::array::from_fn(|idx| self.read_u8(segment, offset.wrapping_add(idx as u16)))
+    }
+
+    // Byte or word by the operand width of REGISTER_MAP
+    pub fn read(&self, segment: u16, offset: u16, width: usize) -> u16 {
+        match width {
+            0 => self.read_u8(segment, offset) as u16,
+            _ => self.read_u16(segment, offset),
+        }
+    }
+
+    pub fn write(&mut self, segment: u16, offset: u16, width: usize, value: u16) {
+        match width {
+            0 => self.write_u8(segment, offset, value as u8),
+            _ => self.write_u16(segment, offset, value),
+        }
+    }
+
+    // Copies a program image to segment:offset, the offsets wrap within the segment
+    pub fn load(&mut self, segment: u16, offset: u16, bytes: &[u8]) {
+        for (idx, &byte) in bytes.iter().enumerate() {
+            self.write_u8(segment, offset.wrapping_add(idx as u16), byte);
+        }
+    }
+}
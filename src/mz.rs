@@ -0,0 +1,191 @@
+// DOS MZ executables: the header, the relocation table and the load image behind them.
+
+use std::collections::BTreeSet;
+
+const SIGNATURES: [&[u8; 2]; 2] = [b"MZ", b"ZM"];
+const HEADER_SIZE: usize = 0x1C;
+const PAGE_SIZE: usize = 512;
+const PARAGRAPH_SIZE: usize = 16;
+
+pub struct MzHeader {
+    pub last_page_bytes: u16,
+    pub pages: u16,
+    pub relocation_count: u16,
+    pub header_paragraphs: u16,
+    pub min_alloc: u16,
+    pub max_alloc: u16,
+    pub initial_ss: u16,
+    pub initial_sp: u16,
+    pub checksum: u16,
+    pub initial_ip: u16,
+    pub initial_cs: u16,
+    pub relocation_table_offset: u16,
+    pub overlay_number: u16,
+}
+
+pub struct MzExecutable<'a> {
+    pub header: MzHeader,
+    pub relocations: Vec<(u16, u16)>, // segment, offset
+    pub image: &'a [u8],
+    // File offset of the load image
+    pub image_start: usize,
+}
+
+pub fn is_mz(bytes: &[u8]) -> bool {
+    bytes.len() >= 2
+        && SIGNATURES
+            .iter()
+            .any(|signature| bytes[..2] == signature[..])
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+impl<'a> MzExecutable<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Self {
+        if bytes.len() < HEADER_SIZE {
+            panic!("Truncated MZ header");
+        }
+
+        let header = MzHeader {
+            last_page_bytes: read_u16(bytes, 0x02),
+            pages: read_u16(bytes, 0x04),
+            relocation_count: read_u16(bytes, 0x06),
+            header_paragraphs: read_u16(bytes, 0x08),
+            min_alloc: read_u16(bytes, 0x0A),
+            max_alloc: read_u16(bytes, 0x0C),
+            initial_ss: read_u16(bytes, 0x0E),
+            initial_sp: read_u16(bytes, 0x10),
+            checksum: read_u16(bytes, 0x12),
+            initial_ip: read_u16(bytes, 0x14),
+            initial_cs: read_u16(bytes, 0x16),
+            relocation_table_offset: read_u16(bytes, 0x18),
+            overlay_number: read_u16(bytes, 0x1A),
+        };
+
+        let table_start = header.relocation_table_offset as usize;
+        let table_end = table_start + header.relocation_count as usize * 4;
+        if table_end > bytes.len() {
+            panic!("Truncated MZ relocation table");
+        }
+        let relocations = bytes[table_start..table_end]
+            .chunks_exact(4)
+            .map(|entry| (read_u16(entry, 2), read_u16(entry, 0)))
+            .collect();
+
+        // The last page is only partially used unless its byte count is zero. A header without
+        // pages has an empty image, whatever its last page says.
+        let mut file_size = header.pages as usize * PAGE_SIZE;
+        if header.last_page_bytes != 0 {
+            let unused = PAGE_SIZE.saturating_sub(header.last_page_bytes as usize);
+            file_size = file_size.saturating_sub(unused);
+        }
+        let image_start = (header.header_paragraphs as usize * PARAGRAPH_SIZE).min(bytes.len());
+        let image_end = file_size.clamp(image_start, bytes.len());
+
+        MzExecutable {
+            header,
+            relocations,
+            image: &bytes[image_start..image_end],
+            image_start,
+        }
+    }
+
+    // Offset of CS:IP in the load image, segments are relative to the start of the image
+    pub fn entry_offset(&self) -> usize {
+        let entry =
+            self.header.initial_cs as usize * PARAGRAPH_SIZE + self.header.initial_ip as usize;
+        if entry >= self.image.len() {
+            panic!("MZ entry point {entry:#x} lies outside the load image");
+        }
+        entry
+    }
+
+    // Image offsets of the segment words DOS patches with the load segment
+    pub fn relocation_offsets(&self) -> BTreeSet<usize> {
+        self.relocations
+            .iter()
+            .map(|&(segment, offset)| segment as usize * PARAGRAPH_SIZE + offset as usize)
+            .collect()
+    }
+
+    pub fn print_info(&self) {
+        let header = &self.header;
+        println!("MZ header");
+        println!("  bytes on last page      {}", header.last_page_bytes);
+        println!("  pages                   {}", header.pages);
+        println!("  relocations             {}", header.relocation_count);
+        println!("  header paragraphs       {}", header.header_paragraphs);
+        println!("  min alloc paragraphs    {:#06x}", header.min_alloc);
+        println!("  max alloc paragraphs    {:#06x}", header.max_alloc);
+        println!(
+            "  initial SS:SP           {:04x}:{:04x}",
+            header.initial_ss, header.initial_sp
+        );
+        println!("  checksum                {:#06x}", header.checksum);
+        println!(
+            "  initial CS:IP           {:04x}:{:04x}",
+            header.initial_cs, header.initial_ip
+        );
+        println!(
+            "  relocation table offset {:#06x}",
+            header.relocation_table_offset
+        );
+        println!("  overlay number          {}", header.overlay_number);
+        println!("  load image size         {:#x}", self.image.len());
+
+        println!("Relocations");
+        for &(segment, offset) in &self.relocations {
+            let image_offset = segment as usize * PARAGRAPH_SIZE + offset as usize;
+            println!("  {segment:04x}:{offset:04x} (image offset {image_offset:#x})");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuMode;
+    use crate::format::Syntax;
+    use crate::tests::listing;
+
+    #[test]
+    fn parses_the_header_and_finds_the_entry_point() {
+        // Two header paragraphs with one relocation, then a data paragraph and the code segment:
+        // mov ax, seg data / mov ds, ax / mov ax, 0x4c00 / int 0x21
+        let mut file = vec![0; 0x20];
+        file[..2].copy_from_slice(b"MZ");
+        let code = [0xB8, 0x00, 0x00, 0x8E, 0xD8, 0xB8, 0x00, 0x4C, 0xCD, 0x21];
+        let size = 0x30 + code.len() as u16;
+        for (offset, value) in [
+            (0x02, size),
+            (0x04, 1),
+            (0x06, 1),
+            (0x08, 2),
+            (0x16, 1),
+            (0x18, 0x1C),
+        ] {
+            file[offset..offset + 2].copy_from_slice(&u16::to_le_bytes(value));
+        }
+        // The segment word of the first mov, at 0001:0001
+        file[0x1C..0x20].copy_from_slice(&[0x01, 0x00, 0x01, 0x00]);
+        file.extend_from_slice(&[0; 0x10]);
+        file.extend_from_slice(&code);
+
+        let exe = MzExecutable::parse(&file);
+        assert_eq!(exe.image_start, 0x20);
+        assert_eq!(exe.image.len(), 0x10 + code.len());
+        assert_eq!(exe.relocations, [(1, 1)]);
+        assert_eq!(
+            exe.relocation_offsets().into_iter().collect::<Vec<_>>(),
+            [0x11]
+        );
+        let entry = exe.entry_offset();
+        assert_eq!(entry, 0x10);
+        assert_eq!(
+            listing(&exe.image[entry..], CpuMode::I8086, Syntax::Intel),
+            ["mov ax, 0", "mov ds, ax", "mov ax, 19456", "int 33"]
+        );
+    }
+}
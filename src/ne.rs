@@ -0,0 +1,365 @@
+// 16-bit Windows NE executables: the new header behind the MZ stub, its segment, entry and name
+// tables and the per-segment relocation records.
+
+use std::collections::BTreeMap;
+
+use crate::format::{Fixup, FixupKind};
+
+const NE_SIGNATURE: &[u8; 2] = b"NE";
+const NE_OFFSET_FIELD: usize = 0x3C;
+const NE_HEADER_SIZE: usize = 0x40;
+const SEGMENT_ENTRY_SIZE: usize = 8;
+const RELOCATION_SIZE: usize = 8;
+const SEGMENT_DATA: u16 = 0x0001;
+const SEGMENT_HAS_RELOCATIONS: u16 = 0x0100;
+const MOVABLE_BUNDLE: u8 = 0xFF;
+const END_OF_CHAIN: u16 = 0xFFFF;
+
+pub struct NeSegment {
+    pub number: usize,
+    pub is_data: bool,
+    pub data: Vec<u8>,
+    // Offsets of the entry points in this segment, by name or ordinal
+    pub labels: BTreeMap<usize, String>,
+    pub fixups: BTreeMap<usize, Fixup>,
+}
+
+pub struct NeExecutable {
+    pub module_name: String,
+    pub description: String,
+    pub entry: (usize, u16), // segment number, offset
+    pub segments: Vec<NeSegment>,
+    pub imported_modules: Vec<String>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    match bytes.get(offset..offset + 2) {
+        Some(word) => u16::from_le_bytes([word[0], word[1]]),
+        None => panic!("Truncated NE executable at offset {offset:#x}"),
+    }
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    *bytes
+        .get(offset)
+        .unwrap_or_else(|| panic!("Truncated NE executable at offset {offset:#x}"))
+}
+
+// A length-prefixed string
+fn read_name(bytes: &[u8], offset: usize) -> String {
+    let length = read_u8(bytes, offset) as usize;
+    match bytes.get(offset + 1..offset + 1 + length) {
+        Some(name) => String::from_utf8_lossy(name).into_owned(),
+        None => panic!("Truncated NE name at offset {offset:#x}"),
+    }
+}
+
+// The MZ stub points to the new header at 0x3C
+pub fn ne_offset(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < NE_HEADER_SIZE {
+        return None;
+    }
+    let offset = u32::from_le_bytes(
+        bytes[NE_OFFSET_FIELD..NE_OFFSET_FIELD + 4]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    (bytes.get(offset..offset + 2) == Some(&NE_SIGNATURE[..])).then_some(offset)
+}
+
+// Names and ordinals, the first entry of the resident table is the module name
+fn read_name_table(bytes: &[u8], mut offset: usize, names: &mut BTreeMap<u16, String>) -> String {
+    let mut first = None;
+    while read_u8(bytes, offset) != 0 {
+        let name = read_name(bytes, offset);
+        offset += 1 + name.len();
+        let ordinal = read_u16(bytes, offset);
+        offset += 2;
+        if first.is_none() {
+            first = Some(name);
+        } else {
+            names.insert(ordinal, name);
+        }
+    }
+    first.unwrap_or_default()
+}
+
+impl NeExecutable {
+    pub fn parse(bytes: &[u8], header: usize) -> Self {
+        let field = |offset: usize| read_u16(bytes, header + offset);
+        let entry_table = header + field(0x04) as usize;
+        let entry_table_end = entry_table + field(0x06) as usize;
+        let entry_ip = field(0x14);
+        let entry_cs = field(0x16) as usize;
+        let segment_count = field(0x1C) as usize;
+        let module_count = field(0x1E) as usize;
+        let segment_table = header + field(0x22) as usize;
+        let resident_names = header + field(0x26) as usize;
+        let module_table = header + field(0x28) as usize;
+        let imported_names = header + field(0x2A) as usize;
+        let nonresident_names = read_u16(bytes, header + 0x2C) as usize
+            | (read_u16(bytes, header + 0x2E) as usize) << 16;
+        // A shift of 0 means the default of 512-byte sectors
+        let alignment_shift = match field(0x32) {
+            0 => 9,
+            shift => shift,
+        };
+
+        let mut names = BTreeMap::new();
+        let module_name = read_name_table(bytes, resident_names, &mut names);
+        // The first non-resident name is the module description
+        let description = if field(0x20) != 0 {
+            read_name_table(bytes, nonresident_names, &mut names)
+        } else {
+            String::new()
+        };
+
+        let imported_modules: Vec<String> = (0..module_count)
+            .map(|idx| {
+                let name_offset = read_u16(bytes, module_table + idx * 2) as usize;
+                read_name(bytes, imported_names + name_offset)
+            })
+            .collect();
+
+        // Entry points by segment and offset, numbered from 1 in table order
+        let mut entries = BTreeMap::new();
+        let mut offset = entry_table;
+        let mut ordinal = 1u16;
+        while offset < entry_table_end && read_u8(bytes, offset) != 0 {
+            let count = read_u8(bytes, offset) as u16;
+            let indicator = read_u8(bytes, offset + 1);
+            offset += 2;
+            for _ in 0..count {
+                match indicator {
+                    0 => {}
+                    // flags, int 3Fh, segment, offset
+                    MOVABLE_BUNDLE => {
+                        let segment = read_u8(bytes, offset + 3) as usize;
+                        entries.insert(ordinal, (segment, read_u16(bytes, offset + 4)));
+                        offset += 6;
+                    }
+                    // flags, offset
+                    segment => {
+                        entries.insert(ordinal, (segment as usize, read_u16(bytes, offset + 1)));
+                        offset += 3;
+                    }
+                }
+                ordinal += 1;
+            }
+        }
+        let entry_name = |ordinal: u16| match names.get(&ordinal) {
+            Some(name) => name.clone(),
+            None => format!("{module_name}.{ordinal}"),
+        };
+        let internal_name = |segment: usize, offset: u16| {
+            entries
+                .iter()
+                .find(|(_, entry)| **entry == (segment, offset))
+                .map_or_else(
+                    || format!("seg{segment}:{offset:#x}"),
+                    |(&ordinal, _)| entry_name(ordinal),
+                )
+        };
+
+        let mut segments = Vec::new();
+        for idx in 0..segment_count {
+            let entry = segment_table + idx * SEGMENT_ENTRY_SIZE;
+            let sector = read_u16(bytes, entry) as usize;
+            let length = match read_u16(bytes, entry + 2) {
+                0 if sector != 0 => 0x10000,
+                length => length as usize,
+            };
+            let flags = read_u16(bytes, entry + 4);
+            let number = idx + 1;
+
+            // Segments without a sector only reserve memory
+            let start = sector << alignment_shift;
+            let data = if sector == 0 {
+                Vec::new()
+            } else {
+                match bytes.get(start..start + length) {
+                    Some(data) => data.to_vec(),
+                    None => panic!("NE segment {number} lies outside the file"),
+                }
+            };
+
+            let labels = entries
+                .iter()
+                .filter(|(_, (segment, _))| *segment == number)
+                .map(|(&ordinal, &(_, offset))| (offset as usize, entry_name(ordinal)))
+                .collect();
+
+            let mut fixups = BTreeMap::new();
+            if sector != 0 && flags & SEGMENT_HAS_RELOCATIONS != 0 {
+                let table = start + length;
+                let count = read_u16(bytes, table) as usize;
+                for record_idx in 0..count {
+                    let record = table + 2 + record_idx * RELOCATION_SIZE;
+                    let source = read_u8(bytes, record);
+                    let flags = read_u8(bytes, record + 1);
+                    let location = read_u16(bytes, record + 2);
+                    let first = read_u16(bytes, record + 4);
+                    let second = read_u16(bytes, record + 6);
+
+                    let kind = match source & 0x0F {
+                        0 => FixupKind::LowByte,
+                        2 => FixupKind::Segment,
+                        3 => FixupKind::Pointer,
+                        5 => FixupKind::Offset,
+                        source => panic!("Unsupported NE relocation source type {source}"),
+                    };
+                    let symbol = match flags & 0b11 {
+                        0 if first & 0xFF == MOVABLE_BUNDLE as u16 => entry_name(second),
+                        // A segment base only names the segment
+                        0 if kind == FixupKind::Segment => format!("seg{}", first & 0xFF),
+                        0 => internal_name((first & 0xFF) as usize, second),
+                        1 => format!("{}.{second}", module(&imported_modules, first)),
+                        2 => format!(
+                            "{}.{}",
+                            module(&imported_modules, first),
+                            read_name(bytes, imported_names + second as usize)
+                        ),
+                        _ => format!("osfixup{second}"),
+                    };
+
+                    // Without the additive flag the patched words form a chain of locations
+                    let additive = flags & 0b100 != 0;
+                    let mut location = location;
+                    loop {
+                        let fixup = Fixup {
+                            kind,
+                            symbol: symbol.clone(),
+                        };
+                        fixups.insert(location as usize, fixup);
+                        if additive || kind == FixupKind::LowByte {
+                            break;
+                        }
+                        let next = read_u16(&data, location as usize);
+                        if next == END_OF_CHAIN || fixups.contains_key(&(next as usize)) {
+                            break;
+                        }
+                        location = next;
+                    }
+                }
+            }
+
+            segments.push(NeSegment {
+                number,
+                is_data: flags & SEGMENT_DATA != 0,
+                data,
+                labels,
+                fixups,
+            });
+        }
+
+        NeExecutable {
+            module_name,
+            description,
+            entry: (entry_cs, entry_ip),
+            segments,
+            imported_modules,
+        }
+    }
+
+    pub fn print_info(&self) {
+        println!("NE header");
+        println!("  module                  {}", self.module_name);
+        println!("  description             {}", self.description);
+        println!(
+            "  entry point             segment {} offset {:#06x}",
+            self.entry.0, self.entry.1
+        );
+        println!("Segments");
+        for segment in &self.segments {
+            println!(
+                "  {:<3} {} {:#07x} bytes, {} entry points, {} relocations",
+                segment.number,
+                if segment.is_data { "data" } else { "code" },
+                segment.data.len(),
+                segment.labels.len(),
+                segment.fixups.len()
+            );
+        }
+        println!("Imported modules");
+        for name in &self.imported_modules {
+            println!("  {name}");
+        }
+    }
+}
+
+fn module(imported_modules: &[String], index: u16) -> &str {
+    imported_modules
+        .get((index as usize).wrapping_sub(1))
+        .unwrap_or_else(|| panic!("NE module reference {index} out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Formatter, Syntax};
+    use crate::{CpuMode, decode_before};
+
+    // An MZ stub, the NE header and its tables, and at 200h one code segment that calls
+    // KERNEL.4 and returns, with the relocation behind it. The alignment shift is left at 0.
+    fn module() -> Vec<u8> {
+        let mut file = vec![0; 0x200];
+        file[..2].copy_from_slice(b"MZ");
+        file[NE_OFFSET_FIELD] = 0x40;
+        let header = 0x40;
+        file[header..header + 2].copy_from_slice(NE_SIGNATURE);
+        let tables: [(usize, &[u8]); 5] = [
+            // Segment table: sector 1, 6 bytes, code with relocations
+            (0x40, &[0x01, 0x00, 0x06, 0x00, 0x00, 0x01, 0x06, 0x00]),
+            // Resident names: the module name and Main as ordinal 1
+            (0x48, b"\x04TEST\x00\x00\x04Main\x01\x00\x00"),
+            // Module reference table and imported names
+            (0x58, &[0x01, 0x00]),
+            (0x5A, b"\x00\x06KERNEL"),
+            // Entry table: ordinal 1 at 1:0000
+            (0x62, &[0x01, 0x01, 0x01, 0x00, 0x00, 0x00]),
+        ];
+        for (offset, table) in tables {
+            file[header + offset..header + offset + table.len()].copy_from_slice(table);
+        }
+        for (offset, value) in [
+            (0x04, 0x62),
+            (0x06, 6),
+            (0x16, 1),
+            (0x1C, 1),
+            (0x1E, 1),
+            (0x22, 0x40),
+            (0x24, 0x48),
+            (0x26, 0x48),
+            (0x28, 0x58),
+            (0x2A, 0x5A),
+        ] {
+            file[header + offset..header + offset + 2].copy_from_slice(&u16::to_le_bytes(value));
+        }
+        // call far KERNEL.4 / retf, and an imported-ordinal pointer relocation at offset 1
+        file.extend_from_slice(&[0x9A, 0xFF, 0xFF, 0x00, 0x00, 0xCB]);
+        file.extend_from_slice(&[0x01, 0x00, 0x03, 0x01, 0x01, 0x00, 0x01, 0x00, 0x04, 0x00]);
+        file
+    }
+
+    #[test]
+    fn segments_relocations_and_entry_points() {
+        let file = module();
+        assert_eq!(ne_offset(&file), Some(0x40));
+        let exe = NeExecutable::parse(&file, 0x40);
+        assert_eq!(exe.module_name, "TEST");
+        assert_eq!(exe.imported_modules, ["KERNEL"]);
+        assert_eq!(exe.entry, (1, 0));
+
+        // A shift of 0 counts in 512-byte sectors
+        let segment = &exe.segments[0];
+        assert!(!segment.is_data);
+        assert_eq!(segment.data, [0x9A, 0xFF, 0xFF, 0x00, 0x00, 0xCB]);
+        assert_eq!(segment.labels.get(&0).map(String::as_str), Some("Main"));
+
+        let formatter = Formatter::new(Syntax::Intel, Some(0)).with_fixups(segment.fixups.clone());
+        let mut line = String::new();
+        let call = decode_before(&segment.data, CpuMode::I8086);
+        formatter.format(&call, 0, &mut line);
+        assert_eq!(line, "call far KERNEL.4");
+    }
+}
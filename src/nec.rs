@@ -0,0 +1,185 @@
+// NEC V20/V30 support: the 0x0F extended opcodes and the NEC assembler naming.
+//
+// In 8080 emulation mode RETEM (ED FD) and CALLN (ED ED imm8) are also available, but those
+// opcodes mean `in ax, dx` in native mode, so only BRKEM, which enters emulation mode, shows up
+// in a native instruction stream.
+
+use crate::{
+    Instruction, Operand, Prefixes, W_BIT_MASK,
+    decode_effective_address_calculation, regmem_operand,
+};
+
+const BIT_OP_NAMES: [&str; 4] = ["test1", "clr1", "set1", "not1"];
+
+pub const NEC_REGISTER_MAP: [[&str; 2]; 8] = [
+    ["al", "aw"],
+    ["cl", "cw"],
+    ["dl", "dw"],
+    ["bl", "bw"],
+    ["ah", "sp"],
+    ["ch", "bp"],
+    ["dh", "ix"],
+    ["bh", "iy"],
+];
+pub const NEC_EFFECTIVE_ADDRESS_BASES: [&str; 8] = [
+    "bw + ix", "bw + iy", "bp + ix", "bp + iy", "ix", "iy", "bp", "bw",
+];
+pub const NEC_SEGMENT_REGS: [&str; 6] = ["ds1", "ps", "ss", "ds0", "fs", "gs"];
+
+// Intel mnemonic -> NEC mnemonic, anything missing is spelled the same way by both
+const NEC_MNEMONICS: [(&str, &str); 80] = [
+    ("xchg", "xch"),
+    ("xlat", "trans"),
+    ("lea", "ldea"),
+    ("lds", "mov ds0,"),
+    ("les", "mov ds1,"),
+    ("lahf", "mov ah, psw"),
+    ("sahf", "mov psw, ah"),
+    ("pushf", "push psw"),
+    ("popf", "pop psw"),
+    ("pusha", "push r"),
+    ("popa", "pop r"),
+    ("enter", "prepare"),
+    ("leave", "dispose"),
+    ("bound", "chkind"),
+    ("insb", "inmb"),
+    ("insw", "inmw"),
+    ("outsb", "outmb"),
+    ("outsw", "outmw"),
+    ("adc", "addc"),
+    ("sbb", "subc"),
+    ("mul", "mulu"),
+    ("imul", "mul"),
+    ("div", "divu"),
+    ("idiv", "div"),
+    ("cbw", "cvtbw"),
+    ("cwd", "cvtwl"),
+    ("aaa", "adjba"),
+    ("daa", "adj4a"),
+    ("aas", "adjbs"),
+    ("das", "adj4s"),
+    ("aam", "cvtbd"),
+    ("aad", "cvtdb"),
+    ("rcl", "rolc"),
+    ("rcr", "rorc"),
+    ("sar", "shra"),
+    ("movsb", "movbkb"),
+    ("movsw", "movbkw"),
+    ("cmpsb", "cmpbkb"),
+    ("cmpsw", "cmpbkw"),
+    ("scasb", "cmpmb"),
+    ("scasw", "cmpmw"),
+    ("lodsb", "ldmb"),
+    ("lodsw", "ldmw"),
+    ("stosb", "stmb"),
+    ("stosw", "stmw"),
+    ("jmp", "br"),
+    ("iret", "reti"),
+    ("int", "brk"),
+    ("int3", "brk 3"),
+    ("into", "brkv"),
+    ("hlt", "halt"),
+    ("wait", "poll"),
+    ("lock", "buslock"),
+    ("cli", "di"),
+    ("sti", "ei"),
+    ("clc", "clr1 cy"),
+    ("stc", "set1 cy"),
+    ("cmc", "not1 cy"),
+    ("cld", "clr1 dir"),
+    ("std", "set1 dir"),
+    ("jo", "bv"),
+    ("jno", "bnv"),
+    ("jb", "bc"),
+    ("jnb", "bnc"),
+    ("je", "be"),
+    ("jne", "bne"),
+    ("jbe", "bnh"),
+    ("ja", "bh"),
+    ("js", "bn"),
+    ("jns", "bp"),
+    ("jp", "bpe"),
+    ("jnp", "bpo"),
+    ("jl", "blt"),
+    ("jge", "bge"),
+    ("jle", "ble"),
+    ("jg", "bgt"),
+    ("loopnz", "dbnzne"),
+    ("loopz", "dbnze"),
+    ("loop", "dbnz"),
+    ("jcxz", "bcwz"),
+];
+
+pub fn nec_mnemonic(inst_name: &'static str) -> &'static str {
+    NEC_MNEMONICS
+        .iter()
+        .find(|(intel, _)| *intel == inst_name)
+        .map_or(inst_name, |(_, nec)| nec)
+}
+
+pub fn decode_extended(bytes: &mut &[u8], prefixes: &Prefixes) -> Instruction {
+    let encoding = *bytes;
+    let byte2 = bytes[1];
+    *bytes = &bytes[2..];
+
+    match byte2 {
+        // test1/clr1/set1/not1 r/m, cl
+        0x10..=0x17 => {
+            let w_bit = (byte2 & W_BIT_MASK) as usize;
+            let inst_name = BIT_OP_NAMES[((byte2 >> 1) & 0b11) as usize];
+            let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, w_bit, true);
+
+            Instruction::new(inst_name, vec![dst, Operand::Reg(1, 0)])
+        }
+        // test1/clr1/set1/not1 r/m, imm3 (imm4 for words)
+        0x18..=0x1F => {
+            let w_bit = (byte2 & W_BIT_MASK) as usize;
+            let inst_name = BIT_OP_NAMES[((byte2 >> 1) & 0b11) as usize];
+            let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, w_bit, true);
+            let bit_idx = bytes[0];
+            *bytes = &bytes[1..];
+
+            Instruction::new(inst_name, vec![dst, Operand::Imm(bit_idx as i32)])
+        }
+        0x20 => Instruction::new("add4s", vec![]),
+        0x22 => Instruction::new("sub4s", vec![]),
+        0x26 => Instruction::new("cmp4s", vec![]),
+        0x28 | 0x2A => {
+            let inst_name = if byte2 == 0x28 { "rol4" } else { "ror4" };
+            let (reg_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            // The reg field is part of the opcode and must be 0
+            if reg_idx != 0 {
+                return Instruction::invalid(&encoding[..encoding.len() - bytes.len()]);
+            }
+
+            Instruction::new(inst_name, vec![regmem_operand(eff_add, 0, true)])
+        }
+        // ins/ext reg8, reg8
+        0x31 | 0x33 => {
+            let inst_name = if byte2 == 0x31 { "ins" } else { "ext" };
+            let (reg_idx, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, 0, false);
+
+            Instruction::new(inst_name, vec![dst, Operand::Reg(reg_idx, 0)])
+        }
+        // ins/ext reg8, imm4
+        0x39 | 0x3B => {
+            let inst_name = if byte2 == 0x39 { "ins" } else { "ext" };
+            let (_, eff_add) = decode_effective_address_calculation(bytes, prefixes);
+            let dst = regmem_operand(eff_add, 0, false);
+            let bit_length = bytes[0];
+            *bytes = &bytes[1..];
+
+            Instruction::new(inst_name, vec![dst, Operand::Imm(bit_length as i32)])
+        }
+        0xFF => {
+            let vector = bytes[0];
+            *bytes = &bytes[1..];
+
+            Instruction::new("brkem", vec![Operand::Imm(vector as i32)])
+        }
+        _ => Instruction::invalid(&encoding[..2]),
+    }
+}
@@ -0,0 +1,469 @@
+// Intel/Microsoft OMF object files: the records that describe segments, names and fixups.
+
+use std::collections::BTreeMap;
+
+use crate::format::{Fixup, FixupKind};
+
+const THEADR: u8 = 0x80;
+const COMENT: u8 = 0x88;
+const MODEND: u8 = 0x8A;
+const EXTDEF: u8 = 0x8C;
+const PUBDEF: u8 = 0x90;
+const LINNUM: u8 = 0x94;
+const LNAMES: u8 = 0x96;
+const SEGDEF: u8 = 0x98;
+const GRPDEF: u8 = 0x9A;
+const FIXUPP: u8 = 0x9C;
+const LEDATA: u8 = 0xA0;
+const LIDATA: u8 = 0xA2;
+const LEXTDEF: u8 = 0xB4;
+const LPUBDEF: u8 = 0xB6;
+
+pub struct Segment {
+    pub name: String,
+    pub class: String,
+    pub data: Vec<u8>,
+    // Set by LEDATA/LIDATA, segments without data only reserve space
+    pub initialized: bool,
+    pub publics: BTreeMap<usize, String>,
+    // LPUBDEF names, only visible inside the module
+    pub locals: BTreeMap<usize, String>,
+    pub fixups: BTreeMap<usize, Fixup>,
+}
+
+pub struct ObjectModule {
+    pub name: String,
+    pub segments: Vec<Segment>,
+    pub groups: Vec<(String, Vec<usize>)>,
+    pub externs: Vec<String>,
+}
+
+// Every object module starts with a THEADR record holding the module name
+pub fn is_omf(bytes: &[u8]) -> bool {
+    if bytes.len() < 5 || bytes[0] != THEADR {
+        return false;
+    }
+    let length = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+    length >= 2 && bytes[3] as usize == length - 2 && 3 + length <= bytes.len() && {
+        let checksum = bytes[..3 + length]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        checksum == 0 || bytes[2 + length] == 0
+    }
+}
+
+// Reads the fields of one record, the odd record types use 32-bit offsets
+struct Reader<'a> {
+    bytes: &'a [u8],
+    wide: bool,
+}
+
+impl Reader<'_> {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn u8(&mut self) -> u8 {
+        let Some((&byte, rest)) = self.bytes.split_first() else {
+            panic!("Truncated OMF record");
+        };
+        self.bytes = rest;
+        byte
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    // 2 bytes, or 4 in the 32-bit variant of a record
+    fn offset(&mut self) -> usize {
+        if self.wide {
+            self.u16() as usize | (self.u16() as usize) << 16
+        } else {
+            self.u16() as usize
+        }
+    }
+
+    // One byte below 0x80, two bytes with the high bit of the first one set otherwise
+    fn index(&mut self) -> usize {
+        let first = self.u8() as usize;
+        if first & 0x80 == 0 {
+            first
+        } else {
+            (first & 0x7F) << 8 | self.u8() as usize
+        }
+    }
+
+    fn name(&mut self) -> String {
+        let length = self.u8() as usize;
+        if length > self.bytes.len() {
+            panic!("Truncated OMF record");
+        }
+        let (name, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8_lossy(name).into_owned()
+    }
+
+    fn take(&mut self, length: usize) -> &[u8] {
+        if length > self.bytes.len() {
+            panic!("Truncated OMF record");
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        taken
+    }
+}
+
+// The target of a fixup as given by a thread or the fixup itself: method and index
+#[derive(Clone, Copy)]
+struct Target {
+    method: u8,
+    index: usize,
+}
+
+impl ObjectModule {
+    pub fn parse(bytes: &[u8]) -> Self {
+        let mut module = ObjectModule {
+            name: String::new(),
+            segments: Vec::new(),
+            groups: Vec::new(),
+            externs: Vec::new(),
+        };
+        let mut lnames = Vec::new();
+        // Segment and offset of the last LEDATA record, which FIXUPP records refer to
+        let mut last_data = None;
+        let mut target_threads = [None; 4];
+
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                panic!("Truncated OMF record header");
+            }
+            let record_type = rest[0];
+            let length = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            if length == 0 || 3 + length > rest.len() {
+                panic!("Truncated OMF record of type {record_type:#04x}");
+            }
+            // The last byte of a record is its checksum
+            let mut record = Reader {
+                bytes: &rest[3..2 + length],
+                wide: record_type & 1 == 1,
+            };
+            rest = &rest[3 + length..];
+
+            match record_type & !1 {
+                THEADR => module.name = record.name(),
+                LNAMES => {
+                    while !record.is_empty() {
+                        lnames.push(record.name());
+                    }
+                }
+                SEGDEF => {
+                    let acbp = record.u8();
+                    // Absolute segments carry their frame number and offset
+                    if acbp >> 5 == 0 {
+                        record.take(3);
+                    }
+                    let mut length = record.offset();
+                    // The big bit stands for a full 64 KiB segment
+                    if acbp & 0b10 != 0 {
+                        if record.wide {
+                            panic!("4 GiB OMF segments are not supported");
+                        }
+                        length = 1 << 16;
+                    }
+                    let name = lname(&lnames, record.index());
+                    let class = lname(&lnames, record.index());
+                    module.segments.push(Segment {
+                        name,
+                        class,
+                        data: vec![0; length],
+                        initialized: false,
+                        publics: BTreeMap::new(),
+                        locals: BTreeMap::new(),
+                        fixups: BTreeMap::new(),
+                    });
+                }
+                GRPDEF => {
+                    let name = lname(&lnames, record.index());
+                    let mut segments = Vec::new();
+                    while !record.is_empty() {
+                        record.u8(); // 0xFF, a segment index follows
+                        segments.push(record.index() - 1);
+                    }
+                    module.groups.push((name, segments));
+                }
+                EXTDEF | LEXTDEF => {
+                    while !record.is_empty() {
+                        module.externs.push(record.name());
+                        record.index(); // type
+                    }
+                }
+                PUBDEF | LPUBDEF => {
+                    record.index(); // group
+                    let segment = record.index();
+                    if segment == 0 {
+                        record.u16(); // frame of an absolute symbol
+                    }
+                    while !record.is_empty() {
+                        let name = record.name();
+                        let offset = record.offset();
+                        record.index(); // type
+                        if segment != 0 {
+                            let segment = module.segment(segment);
+                            match record_type & !1 {
+                                PUBDEF => segment.publics.insert(offset, name),
+                                _ => segment.locals.insert(offset, name),
+                            };
+                        }
+                    }
+                }
+                LEDATA => {
+                    let segment = record.index();
+                    let offset = record.offset();
+                    let data = record.bytes;
+                    module.segment(segment).write(offset, data);
+                    last_data = Some((segment, offset));
+                }
+                LIDATA => {
+                    let segment = record.index();
+                    let offset = record.offset();
+                    let mut data = Vec::new();
+                    while !record.is_empty() {
+                        expand_iterated(&mut record, &mut data);
+                    }
+                    module.segment(segment).write(offset, &data);
+                    // Fixups of iterated data refer to the unexpanded blocks, those are skipped
+                    last_data = None;
+                }
+                FIXUPP => {
+                    while !record.is_empty() {
+                        let first = record.u8();
+                        if first & 0x80 == 0 {
+                            read_thread(&mut record, first, &mut target_threads);
+                            continue;
+                        }
+
+                        let locat = (first as usize) << 8 | record.u8() as usize;
+                        let fixup = read_fixup(&mut record, locat, &target_threads);
+                        if let Some((segment, offset)) = last_data {
+                            let symbol = module.target_name(fixup.0);
+                            let symbol = match fixup.1 {
+                                0 => symbol,
+                                displacement => format!("{symbol} + {displacement}"),
+                            };
+                            let kind = fixup.2;
+                            let location = offset + (locat & 0x3FF);
+                            module
+                                .segment(segment)
+                                .fixups
+                                .insert(location, Fixup { kind, symbol });
+                        }
+                    }
+                }
+                COMENT | MODEND | LINNUM => {}
+                // COMDEF, COMDAT and the other extensions do not affect the code listing
+                _ => {}
+            }
+        }
+
+        module
+    }
+
+    fn segment(&mut self, index: usize) -> &mut Segment {
+        let count = self.segments.len();
+        self.segments
+            .get_mut(index.wrapping_sub(1))
+            .unwrap_or_else(|| panic!("OMF segment index {index} out of range ({count} segments)"))
+    }
+
+    fn target_name(&self, target: Target) -> String {
+        // Method 3 gives a frame number, the others an index starting at 1
+        if target.method == 3 {
+            return format!("{:#x}", target.index);
+        }
+        let Some(index) = target.index.checked_sub(1) else {
+            panic!("Malformed OMF fixup record: target index 0");
+        };
+        let name = match target.method {
+            0 => self.segments.get(index).map(|segment| &segment.name),
+            1 => self.groups.get(index).map(|group| &group.0),
+            _ => self.externs.get(index),
+        };
+        name.cloned()
+            .unwrap_or_else(|| panic!("OMF fixup target index {} out of range", target.index))
+    }
+}
+
+impl Segment {
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        if end > self.data.len() {
+            panic!(
+                "OMF data for segment {} runs past its length of {:#x}",
+                self.name,
+                self.data.len()
+            );
+        }
+        self.data[offset..end].copy_from_slice(data);
+        self.initialized = true;
+    }
+}
+
+fn lname(lnames: &[String], index: usize) -> String {
+    match index {
+        0 => String::new(),
+        _ => lnames
+            .get(index - 1)
+            .unwrap_or_else(|| panic!("OMF name index {index} out of range"))
+            .clone(),
+    }
+}
+
+// A repeat count and either nested blocks or the content bytes
+fn expand_iterated(record: &mut Reader, data: &mut Vec<u8>) {
+    let repeat = record.offset();
+    let block_count = record.u16();
+    let start = data.len();
+    if block_count == 0 {
+        let length = record.u8() as usize;
+        data.extend_from_slice(record.take(length));
+    } else {
+        for _ in 0..block_count {
+            expand_iterated(record, data);
+        }
+    }
+    let block = data[start..].to_vec();
+    for _ in 1..repeat {
+        data.extend_from_slice(&block);
+    }
+}
+
+// Threads hold a frame or target that later fixups can refer to by number, only the targets
+// matter for the listing
+fn read_thread(record: &mut Reader, first: u8, target_threads: &mut [Option<Target>; 4]) {
+    let is_frame = first & 0x40 != 0;
+    let method = (first >> 2) & 0b111;
+    let thread = (first & 0b11) as usize;
+    if is_frame {
+        skip_frame_datum(record, method);
+    } else {
+        target_threads[thread] = Some(read_target(record, method));
+    }
+}
+
+// Only the methods that name a segment, group or extern have an index, method 3 gives a frame
+// number and the others have no datum
+fn skip_frame_datum(record: &mut Reader, method: u8) {
+    match method {
+        0..=2 => {
+            record.index();
+        }
+        3 => {
+            record.u16();
+        }
+        _ => {}
+    }
+}
+
+fn read_target(record: &mut Reader, method: u8) -> Target {
+    let method = method & 0b11;
+    let index = match method {
+        3 => record.u16() as usize,
+        _ => record.index(),
+    };
+    Target { method, index }
+}
+
+// Returns the target, its displacement and how the location is patched
+fn read_fixup(
+    record: &mut Reader,
+    locat: usize,
+    target_threads: &[Option<Target>; 4],
+) -> (Target, usize, FixupKind) {
+    let segment_relative = locat & 0x4000 != 0;
+    let location = (locat >> 10) & 0b1111;
+    let fix_data = record.u8();
+
+    // The frame only tells the linker which segment base to use
+    if fix_data & 0x80 == 0 {
+        skip_frame_datum(record, (fix_data >> 4) & 0b111);
+    }
+    let target = if fix_data & 0x08 != 0 {
+        target_threads[(fix_data & 0b11) as usize].expect("OMF fixup refers to an undefined thread")
+    } else {
+        read_target(record, fix_data & 0b11)
+    };
+    let displacement = if fix_data & 0x04 == 0 {
+        record.offset()
+    } else {
+        0
+    };
+
+    let kind = match location {
+        _ if !segment_relative => FixupKind::Relative,
+        0 => FixupKind::LowByte,
+        4 => FixupKind::HighByte,
+        1 | 5 | 9 | 13 => FixupKind::Offset,
+        2 => FixupKind::Segment,
+        3 | 11 => FixupKind::Pointer,
+        _ => panic!("Unsupported OMF fixup location type {location}"),
+    };
+    (target, displacement, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Formatter, Syntax};
+    use crate::{CpuMode, decode_before};
+
+    // Type, length and checksum around the body
+    fn record(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut record = vec![kind];
+        record.extend_from_slice(&(body.len() as u16 + 1).to_le_bytes());
+        record.extend_from_slice(body);
+        let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        record.push(sum.wrapping_neg());
+        record
+    }
+
+    fn name(text: &str) -> Vec<u8> {
+        [&[text.len() as u8], text.as_bytes()].concat()
+    }
+
+    #[test]
+    fn call_to_an_external_is_written_by_name() {
+        // main: call printf / ret, with a self-relative fixup of the call's offset to extern 1
+        let module = [
+            record(THEADR, &name("hello.asm")),
+            record(LNAMES, &[name(""), name("_TEXT"), name("CODE")].concat()),
+            record(SEGDEF, &[0x48, 0x04, 0x00, 0x02, 0x03, 0x01]),
+            record(EXTDEF, &[name("printf"), vec![0]].concat()),
+            record(PUBDEF, &[vec![0, 1], name("main"), vec![0, 0, 0]].concat()),
+            record(LEDATA, &[0x01, 0x00, 0x00, 0xE8, 0x00, 0x00, 0xC3]),
+            record(FIXUPP, &[0x84, 0x01, 0x56, 0x01]),
+            record(MODEND, &[0x00]),
+        ]
+        .concat();
+        assert!(is_omf(&module));
+
+        let module = ObjectModule::parse(&module);
+        assert_eq!(module.name, "hello.asm");
+        assert_eq!(module.externs, ["printf"]);
+        let segment = &module.segments[0];
+        assert_eq!(
+            (segment.name.as_str(), segment.class.as_str()),
+            ("_TEXT", "CODE")
+        );
+        assert!(segment.initialized);
+        assert_eq!(segment.data, [0xE8, 0x00, 0x00, 0xC3]);
+        assert_eq!(segment.publics.get(&0).map(String::as_str), Some("main"));
+
+        let formatter = Formatter::new(Syntax::Intel, Some(0)).with_fixups(segment.fixups.clone());
+        let mut line = String::new();
+        let call = decode_before(&segment.data, CpuMode::I8086);
+        formatter.format(&call, 0, &mut line);
+        assert_eq!(line, "call printf");
+    }
+}
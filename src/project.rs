@@ -0,0 +1,353 @@
+// Project files: labels, comments, data types and operand display overrides kept next to a
+// binary in JSON, so they do not have to be given again on every run.
+//
+// {
+//   "0x100": { "label": "start", "comment": "print the greeting" },
+//   "0x105": { "operand": "char" },
+//   "0x120": { "label": "message", "data": "str", "end": "0x12e" }
+// }
+
+use std::collections::BTreeMap;
+
+use crate::data::{self, DataKind, DataRange};
+use crate::{parse_number, parse_origin};
+
+// How the immediate or displacement of an instruction is written
+#[derive(Clone, Copy, PartialEq)]
+pub enum OperandDisplay {
+    Hex,
+    Decimal,
+    Char,
+    // The label at that address
+    Symbol,
+}
+
+#[derive(Default)]
+pub struct Annotation {
+    pub label: Option<String>,
+    pub comment: Option<String>,
+    // Exclusive end address and how the bytes are written
+    pub data: Option<(u32, DataKind)>,
+    pub operand: Option<OperandDisplay>,
+}
+
+pub struct Project {
+    // By segment, for addresses given as seg:off, and offset
+    annotations: BTreeMap<(Option<u16>, u32), Annotation>,
+}
+
+impl Project {
+    pub fn parse(text: &str) -> Self {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value();
+        parser.skip_whitespace();
+        if parser.position < parser.text.len() {
+            parser.fail("trailing characters");
+        }
+
+        let Value::Object(entries) = value else {
+            panic!("Invalid project file: expected an object of addresses");
+        };
+        let mut annotations = BTreeMap::new();
+        for (key, value) in entries {
+            let address = parse_origin(&key)
+                .unwrap_or_else(|| panic!("Invalid project file: bad address {key}"));
+            annotations.insert(
+                (address.segment, address.offset),
+                parse_annotation(&key, value),
+            );
+        }
+        Project { annotations }
+    }
+
+    // Annotations given with this segment and the ones given without a segment
+    pub fn annotations(&self, segment: Option<u16>) -> impl Iterator<Item = (u32, &Annotation)> {
+        self.annotations
+            .iter()
+            .filter(move |((key_segment, _), _)| key_segment.is_none() || *key_segment == segment)
+            .map(|(&(_, offset), annotation)| (offset, annotation))
+    }
+
+    // Data ranges as offsets from the address origin
+    pub fn data_ranges(&self, segment: Option<u16>, origin: u32) -> Vec<DataRange> {
+        self.annotations(segment)
+            .filter_map(|(address, annotation)| {
+                let (end, kind) = annotation.data?;
+                Some(DataRange {
+                    start: address.checked_sub(origin)? as usize,
+                    end: (end - origin) as usize,
+                    kind,
+                })
+            })
+            .collect()
+    }
+}
+
+fn parse_annotation(key: &str, value: Value) -> Annotation {
+    let Value::Object(fields) = value else {
+        panic!("Invalid project file: {key} is not an object");
+    };
+    let mut annotation = Annotation::default();
+    let mut data = None;
+    let mut end = None;
+    for (name, value) in fields {
+        let text = match value {
+            Value::String(text) => text,
+            Value::Number(number) => number.to_string(),
+            _ => panic!("Invalid project file: {key}.{name} is not a string"),
+        };
+        match name.as_str() {
+            "label" => annotation.label = Some(text),
+            "comment" => annotation.comment = Some(text),
+            "data" => {
+                data = Some(data::parse_kind(&text).unwrap_or_else(|| {
+                    panic!(
+                        "Invalid project file: {key}.data must be db, dw, dd, ptr, farptr or str"
+                    )
+                }))
+            }
+            "end" => {
+                end = Some(parse_number(&text).unwrap_or_else(|| {
+                    panic!("Invalid project file: bad end address {text} at {key}")
+                }))
+            }
+            "operand" => {
+                annotation.operand = Some(match text.as_str() {
+                    "hex" => OperandDisplay::Hex,
+                    "decimal" => OperandDisplay::Decimal,
+                    "char" => OperandDisplay::Char,
+                    "symbol" => OperandDisplay::Symbol,
+                    _ => panic!(
+                        "Invalid project file: {key}.operand must be hex, decimal, char or symbol"
+                    ),
+                })
+            }
+            _ => panic!("Invalid project file: unknown field {name} at {key}"),
+        }
+    }
+
+    let start = parse_origin(key).map_or(0, |address| address.offset);
+    annotation.data = match (data, end) {
+        (Some(kind), Some(end)) if end > start => Some((end, kind)),
+        (None, None) => None,
+        _ => panic!("Invalid project file: {key} needs both data and an end after it"),
+    };
+    annotation
+}
+
+enum Value {
+    Object(Vec<(String, Value)>),
+    String(String),
+    Number(i64),
+    // Arrays, true, false and null are accepted but carry nothing the project uses
+    Other,
+}
+
+// Just enough of JSON for project files: no floating point numbers
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn fail(&self, reason: &str) -> ! {
+        let line = self.text[..self.position]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count();
+        panic!("Invalid project file on line {}: {reason}", line + 1);
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) {
+        if self.peek() != Some(byte) {
+            self.fail(&format!("expected '{}'", byte as char));
+        }
+        self.position += 1;
+    }
+
+    fn value(&mut self) -> Value {
+        match self.peek() {
+            Some(b'{') => {
+                let mut entries = Vec::new();
+                self.sequence(b'}', |parser| {
+                    let key = parser.string();
+                    parser.expect(b':');
+                    entries.push((key, parser.value()));
+                });
+                Value::Object(entries)
+            }
+            Some(b'[') => {
+                self.sequence(b']', |parser| {
+                    parser.value();
+                });
+                Value::Other
+            }
+            Some(b'"') => Value::String(self.string()),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                self.position += 1;
+                while self
+                    .text
+                    .get(self.position)
+                    .is_some_and(|byte| byte.is_ascii_digit())
+                {
+                    self.position += 1;
+                }
+                let digits = std::str::from_utf8(&self.text[start..self.position]).unwrap();
+                match digits.parse() {
+                    Ok(number) => Value::Number(number),
+                    Err(_) => self.fail("bad number"),
+                }
+            }
+            _ => {
+                for literal in ["true", "false", "null"] {
+                    if self.text[self.position..].starts_with(literal.as_bytes()) {
+                        self.position += literal.len();
+                        return Value::Other;
+                    }
+                }
+                self.fail("expected a value")
+            }
+        }
+    }
+
+    // The opening bracket is next, items are separated by commas
+    fn sequence(&mut self, close: u8, mut item: impl FnMut(&mut Self)) {
+        self.position += 1;
+        if self.peek() == Some(close) {
+            self.position += 1;
+            return;
+        }
+        loop {
+            item(self);
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(byte) if byte == close => {
+                    self.position += 1;
+                    return;
+                }
+                _ => self.fail(&format!("expected ',' or '{}'", close as char)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> String {
+        self.expect(b'"');
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&byte) = self.text.get(self.position) else {
+                self.fail("unterminated string");
+            };
+            self.position += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.text.get(self.position) else {
+                        self.fail("unterminated string");
+                    };
+                    self.position += 1;
+                    let char = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let digits = self
+                                .text
+                                .get(self.position..self.position + 4)
+                                .and_then(|digits| std::str::from_utf8(digits).ok())
+                                .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+                            self.position += 4;
+                            match digits.and_then(char::from_u32) {
+                                Some(char) => char,
+                                None => self.fail("bad \\u escape"),
+                            }
+                        }
+                        b'"' | b'\\' | b'/' => escape as char,
+                        _ => self.fail("bad escape"),
+                    };
+                    bytes.extend_from_slice(char.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        match String::from_utf8(bytes) {
+            Ok(string) => string,
+            Err(_) => self.fail("string is not UTF-8"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Formatter, Syntax};
+    use crate::{CpuMode, decode_before};
+
+    const PROJECT: &str = r#"{
+        "0x100": { "label": "start", "comment": "print the greeting", "operand": "char" },
+        "0x102": { "operand": "hex" },
+        "0x104": { "label": "message", "data": "str", "end": "0x10a" },
+        "2000:0010": { "label": "far_data" }
+    }"#;
+
+    #[test]
+    fn annotations_by_address_and_segment() {
+        let project = Project::parse(PROJECT);
+        assert_eq!(project.annotations(None).count(), 3);
+        assert_eq!(project.annotations(Some(0x2000)).count(), 4);
+        let ranges = project.data_ranges(None, 0x100);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].end), (4, 10));
+        assert!(ranges[0].kind == DataKind::Strings);
+    }
+
+    #[test]
+    fn labels_comments_and_operands_reach_the_listing() {
+        // mov al, 'A' / mov ah, 2 at 100h
+        let bytes = [0xB0, 0x41, 0xB4, 0x02];
+        let formatter = Formatter::new(Syntax::Intel, Some(0x100)).with_project(
+            &Project::parse(PROJECT),
+            None,
+            false,
+        );
+        assert_eq!(formatter.label(0), Some("start"));
+        assert_eq!(formatter.comment(0), Some("print the greeting"));
+        let mut lines = Vec::new();
+        for offset in [0, 2] {
+            let mut line = String::new();
+            formatter.format(
+                &decode_before(&bytes[offset..], CpuMode::I8086),
+                offset,
+                &mut line,
+            );
+            lines.push(line);
+        }
+        assert_eq!(lines, ["mov al, 'A'", "mov ah, 0x2"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs both data and an end")]
+    fn data_without_an_end_is_rejected() {
+        Project::parse(r#"{ "0x10": { "data": "dw" } }"#);
+    }
+}
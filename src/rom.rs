@@ -0,0 +1,164 @@
+// BIOS and option-ROM images: where they are mapped and where the CPU enters them.
+
+const OPTION_ROM_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const OPTION_ROM_BLOCK_SIZE: usize = 512;
+// Option ROMs are found anywhere from C000 up, video BIOSes sit at the start of that range
+const OPTION_ROM_SEGMENT: u16 = 0xC000;
+const OPTION_ROM_ENTRY: usize = 3;
+// The system BIOS ends at the top of the 1 MiB address space
+const BIOS_SEGMENT: u16 = 0xF000;
+const RESET_VECTOR: usize = 0xFFF0;
+const SEGMENT_SIZE: usize = 0x10000;
+
+pub enum RomKind {
+    SystemBios,
+    OptionRom { declared_size: usize, checksum: u8 },
+}
+
+pub struct Rom<'a> {
+    pub kind: RomKind,
+    pub image: &'a [u8],
+    pub segment: u16,
+    // Offset of the first image byte within the segment
+    pub base: u16,
+    // Image offset the CPU starts executing at
+    pub entry: usize,
+}
+
+impl<'a> Rom<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Self {
+        if bytes.starts_with(&OPTION_ROM_SIGNATURE) {
+            if bytes.len() <= OPTION_ROM_ENTRY {
+                panic!("Truncated option ROM header");
+            }
+            let declared_size = bytes[2] as usize * OPTION_ROM_BLOCK_SIZE;
+            // All bytes of a valid option ROM add up to zero
+            let checksum = bytes[..declared_size.min(bytes.len())]
+                .iter()
+                .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+            return Rom {
+                kind: RomKind::OptionRom {
+                    declared_size,
+                    checksum,
+                },
+                image: bytes,
+                segment: OPTION_ROM_SEGMENT,
+                base: 0,
+                entry: OPTION_ROM_ENTRY,
+            };
+        }
+
+        if bytes.len() > SEGMENT_SIZE || RESET_VECTOR < SEGMENT_SIZE - bytes.len() {
+            panic!(
+                "ROM image has {} bytes, a system BIOS image needs to contain the reset vector at F000:FFF0 and fit in 64 KiB",
+                bytes.len()
+            );
+        }
+        let base = SEGMENT_SIZE - bytes.len();
+
+        Rom {
+            kind: RomKind::SystemBios,
+            image: bytes,
+            segment: BIOS_SEGMENT,
+            base: base as u16,
+            entry: RESET_VECTOR - base,
+        }
+    }
+
+    pub fn print_info(&self) {
+        match self.kind {
+            RomKind::SystemBios => println!(
+                "; system BIOS, {} bytes at {:04x}:{:04x}, reset vector at {:04x}:{:04x}",
+                self.image.len(),
+                self.segment,
+                self.base,
+                self.segment,
+                RESET_VECTOR
+            ),
+            RomKind::OptionRom {
+                declared_size,
+                checksum,
+            } => {
+                println!(
+                    "; option ROM, {} blocks ({declared_size} bytes) assumed at {:04x}:0000",
+                    declared_size / OPTION_ROM_BLOCK_SIZE,
+                    self.segment
+                );
+                if declared_size > self.image.len() {
+                    println!(
+                        "; image is truncated, only {} bytes are present",
+                        self.image.len()
+                    );
+                }
+                if checksum == 0 {
+                    println!("; checksum ok");
+                } else {
+                    println!("; checksum mismatch, the bytes add up to {checksum:#04x}");
+                }
+            }
+        }
+    }
+
+    // Image offset of a seg:off address, if it lies inside the image
+    pub fn image_offset(&self, segment: u16, offset: u16) -> Option<usize> {
+        let linear = segment as usize * 16 + offset as usize;
+        let start = self.segment as usize * 16 + self.base as usize;
+        (start..start + self.image.len())
+            .contains(&linear)
+            .then(|| linear - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Syntax;
+    use crate::tests::listing;
+    use crate::{CpuMode, decode_before};
+
+    #[test]
+    fn system_bios_enters_at_the_reset_vector() {
+        // 32 bytes at F000:FFE0, jmp far F000:FFE0 at the reset vector and a mov cut off
+        let mut image = [0x90; 0x20];
+        image[0x10..0x15].copy_from_slice(&[0xEA, 0xE0, 0xFF, 0x00, 0xF0]);
+        image[0x1E..].copy_from_slice(&[0xB8, 0x34]);
+        let rom = Rom::parse(&image);
+        assert!(matches!(rom.kind, RomKind::SystemBios));
+        assert_eq!((rom.segment, rom.base, rom.entry), (0xF000, 0xFFE0, 0x10));
+        assert_eq!(
+            listing(
+                &image[rom.entry..rom.entry + 5],
+                CpuMode::I8086,
+                Syntax::Intel
+            ),
+            ["jmp 61440:65504"]
+        );
+        assert_eq!(rom.image_offset(0xF000, 0xFFE0), Some(0));
+        assert_eq!(rom.image_offset(0xF000, 0xFFDF), None);
+        // Decoded from zero padding, the size tells that it does not fit
+        assert_eq!(decode_before(&image[0x1E..], CpuMode::I8086).size, 3);
+    }
+
+    #[test]
+    fn option_rom_checks_its_size_and_checksum() {
+        // One block, jmp short to the retf at offset 5, and a byte that makes the sum zero
+        let mut image = [0; 512];
+        image[..6].copy_from_slice(&[0x55, 0xAA, 0x01, 0xEB, 0x00, 0xCB]);
+        let sum = image.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        image[511] = sum.wrapping_neg();
+        let rom = Rom::parse(&image);
+        assert!(matches!(
+            rom.kind,
+            RomKind::OptionRom {
+                declared_size: 512,
+                checksum: 0
+            }
+        ));
+        assert_eq!((rom.segment, rom.entry), (0xC000, 3));
+        assert_eq!(
+            listing(&image[3..6], CpuMode::I8086, Syntax::Intel),
+            ["jmp short $+2+0", "retf"]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+// Public symbol addresses from linker map files (MS LINK, TLINK) and NASM map and listing files.
+
+pub enum SymbolAddress {
+    // LINK and TLINK, relative to the load segment of the program
+    SegOff(u16, u16),
+    // The Real column of a NASM map file, origin included
+    Linear(u32),
+    // A NASM listing, counted from the start of the section without the origin
+    SectionOffset(u32),
+}
+
+pub struct Symbol {
+    pub address: SymbolAddress,
+    pub name: String,
+}
+
+pub fn parse(text: &str) -> Vec<Symbol> {
+    if text.contains("NASM Map file") {
+        parse_nasm_map(text)
+    } else if text.lines().any(|line| line.contains(" Publics by ")) {
+        parse_link_map(text)
+    } else {
+        parse_nasm_listing(text)
+    }
+}
+
+fn is_symbol_name(text: &str) -> bool {
+    text.starts_with(|char: char| char.is_ascii_alphabetic() || "_.?$@".contains(char))
+}
+
+//  0000:0010       PrintString
+//  0000:0012  Abs  BufferLen
+fn parse_link_map(text: &str) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    let mut in_publics = false;
+
+    for line in text.lines() {
+        if line.contains(" Publics by ") {
+            // Both the by name and the by value section list every symbol
+            if in_publics {
+                break;
+            }
+            in_publics = true;
+            continue;
+        }
+        if !in_publics {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let Some((segment, offset)) = fields.next().and_then(|field| field.split_once(':')) else {
+            continue;
+        };
+        let (Ok(segment), Ok(offset)) = (
+            u16::from_str_radix(segment, 16),
+            u16::from_str_radix(offset.trim_end_matches(['H', 'h']), 16),
+        ) else {
+            continue;
+        };
+        let Some(name) = fields.find(|field| !matches!(*field, "Abs" | "Imp" | "Res")) else {
+            continue;
+        };
+        symbols.push(Symbol {
+            address: SymbolAddress::SegOff(segment, offset),
+            name: name.to_string(),
+        });
+    }
+
+    symbols
+}
+
+// Real              Virtual           Name
+//              100               100  start
+fn parse_nasm_map(text: &str) -> Vec<Symbol> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [real, _, name] = fields[..] else {
+                return None;
+            };
+            let address = u32::from_str_radix(real, 16).ok()?;
+            is_symbol_name(name).then(|| Symbol {
+                address: SymbolAddress::Linear(address),
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+//      5                                  start:
+//      6 00000000 B409                    mov ah, 9
+fn parse_nasm_listing(text: &str) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    // Labels on a line of their own get the address of the next line with code
+    let mut pending = Vec::new();
+
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        if fields
+            .next()
+            .is_none_or(|number| number.parse::<u32>().is_err())
+        {
+            continue;
+        }
+        let Some(field) = fields.next() else {
+            continue;
+        };
+
+        let (address, source) = match u32::from_str_radix(field, 16) {
+            Ok(address) if field.len() == 8 => {
+                let source = fields.skip(1).collect::<Vec<_>>().join(" ");
+                (Some(address), source)
+            }
+            _ => (
+                None,
+                line.split_whitespace()
+                    .skip(1)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+        };
+        if let Some((label, _)) = source.split_once(':')
+            && is_symbol_name(label)
+            && !label.contains([' ', '['])
+        {
+            pending.push(label.to_string());
+        }
+        if let Some(address) = address {
+            symbols.extend(pending.drain(..).map(|name| Symbol {
+                address: SymbolAddress::SectionOffset(address),
+                name,
+            }));
+        }
+    }
+
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Formatter, Syntax};
+    use crate::{CpuMode, decode_before, symbol_labels};
+
+    fn names_and_addresses(symbols: &[Symbol]) -> Vec<(&str, u32)> {
+        symbols
+            .iter()
+            .map(|symbol| {
+                let address = match symbol.address {
+                    SymbolAddress::SegOff(segment, offset) => {
+                        (segment as u32) << 16 | offset as u32
+                    }
+                    SymbolAddress::Linear(address) | SymbolAddress::SectionOffset(address) => {
+                        address
+                    }
+                };
+                (symbol.name.as_str(), address)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn link_map_publics_are_read_once() {
+        let map = " Start  Stop   Length Name               Class
+ 00000H 0001FH 00020H _TEXT              CODE
+
+  Address         Publics by Name
+
+ 0000:0010       PrintString
+ 0001:0002  Abs  BufferLen
+
+  Address         Publics by Value
+
+ 0000:0010       PrintString
+ 0001:0002  Abs  BufferLen
+";
+        let symbols = parse(map);
+        assert!(matches!(symbols[0].address, SymbolAddress::SegOff(0, 0x10)));
+        assert_eq!(
+            names_and_addresses(&symbols),
+            [("PrintString", 0x10), ("BufferLen", 0x1_0002)]
+        );
+    }
+
+    #[test]
+    fn nasm_listing_labels_get_the_next_address() {
+        let listing = "     1                                  org 100h
+     2                                  start:
+     3 00000000 B409                    mov ah, 9
+     4 00000002 CD21                    done: int 21h
+";
+        let symbols = parse(listing);
+        assert!(matches!(
+            symbols[0].address,
+            SymbolAddress::SectionOffset(0)
+        ));
+        assert_eq!(names_and_addresses(&symbols), [("start", 0), ("done", 2)]);
+    }
+
+    #[test]
+    fn nasm_map_names_jump_targets_and_memory_operands() {
+        let map = "- NASM Map file ---------------------------------------------------------------
+
+-- Symbols --------------------------------------------------------------------
+
+---- Section .text ------------------------------------------------------------
+
+Real              Virtual           Name
+             100               100  start
+             106               106  print
+             10A               10A  msg
+";
+        let symbols = parse(map);
+        assert_eq!(
+            names_and_addresses(&symbols),
+            [("start", 0x100), ("print", 0x106), ("msg", 0x10A)]
+        );
+
+        // call print / mov ax, [msg] at 100h
+        let bytes = [0xE8, 0x03, 0x00, 0xA1, 0x0A, 0x01];
+        let (labels, data_labels) = symbol_labels(&symbols, 0x100, None);
+        let formatter = Formatter::new(Syntax::Intel, Some(0x100))
+            .with_labels(labels)
+            .with_data_labels(data_labels);
+        let mut lines = Vec::new();
+        for offset in [0, 3] {
+            let mut line = String::new();
+            formatter.format(
+                &decode_before(&bytes[offset..], CpuMode::I8086),
+                offset,
+                &mut line,
+            );
+            lines.push(line);
+        }
+        assert_eq!(lines, ["call print", "mov ax, [msg]"]);
+        assert_eq!(formatter.label(0), Some("start"));
+    }
+}